@@ -3,9 +3,107 @@ use std::sync::{Arc, Mutex};
 use base64::Engine;
 use reqwest_retry::{RetryTransientMiddleware, RetryableStrategy, policies::ExponentialBackoff};
 
-use crate::config;
+use crate::{config, pct};
 
-type ReqError = reqwest_middleware::Error;
+#[derive(Debug)]
+pub enum Error {
+    /// The request never got a response: connection refused, timeout, TLS failure...
+    Transport(reqwest_middleware::Error),
+
+    /// Proxmox answered with a non-success status.
+    Status {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+
+    /// Proxmox rejected our credentials.
+    Auth(String),
+
+    /// The guest does not exist on the node we asked, probably because it migrated.
+    NotFound(String),
+
+    /// The QEMU guest agent inside the VM is not running or not answering.
+    AgentNotRunning(String),
+
+    /// The response did not have the shape we expected.
+    Malformed(String),
+
+    /// A command on the host, like `pct`, could not be started.
+    Local(std::io::Error),
+
+    /// A command inside the guest ran, but failed.
+    CommandFailed(String),
+
+    /// `proxmox_auth` can't be used, like without any credentials.
+    Config(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(why) => write!(f, "failed to reach Proxmox API: {why}"),
+            Error::Status { status, message } => {
+                write!(f, "Proxmox API returned {status}: {message}")
+            }
+            Error::Auth(why) => write!(f, "Proxmox API rejected our credentials: {why}"),
+            Error::NotFound(why) => write!(f, "guest not found: {why}"),
+            Error::AgentNotRunning(why) => write!(f, "guest agent is not running: {why}"),
+            Error::Malformed(why) => write!(f, "malformed response from Proxmox API: {why}"),
+            Error::Local(why) => write!(f, "failed to run command on the host: {why}"),
+            Error::CommandFailed(why) => write!(f, "command in the guest failed: {why}"),
+            Error::Config(why) => write!(f, "invalid proxmox_auth: {why}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest_middleware::Error> for Error {
+    fn from(value: reqwest_middleware::Error) -> Self {
+        Error::Transport(value)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Error::Transport(reqwest_middleware::Error::Reqwest(value))
+    }
+}
+
+/// Turn a non-success response into the matching [`Error`].
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    // Proxmox puts the human-readable reason into the `message` field,
+    // but fall back to the raw body if it's not there.
+    let body = res.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["message"].as_str().map(|m| m.trim().to_string()))
+        .unwrap_or(body);
+
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        Err(Error::Auth(message))
+    } else if message.contains("does not exist") {
+        Err(Error::NotFound(message))
+    } else if message.contains("guest agent is not running") || message.contains("got timeout") {
+        Err(Error::AgentNotRunning(message))
+    } else {
+        Err(Error::Status { status, message })
+    }
+}
+
+/// Parse the response body as JSON and return its `data` field.
+async fn response_data(res: reqwest::Response) -> Result<serde_json::Value, Error> {
+    let mut json: serde_json::Value = res
+        .json()
+        .await
+        .map_err(|why| Error::Malformed(why.to_string()))?;
+    Ok(json["data"].take())
+}
 
 #[derive(Clone)]
 pub struct Api {
@@ -15,13 +113,92 @@ pub struct Api {
 
 struct Inner {
     base_url: String,
-    username: String,
-    password: String,
+    credentials: Credentials,
     ticket: Mutex<Option<String>>,
     csrf: Mutex<Option<String>>,
     ticket_expiry: Mutex<std::time::Instant>,
 }
 
+enum Credentials {
+    /// Log in with a password to get a `PVEAuthCookie` ticket.
+    Password { username: String, password: String },
+
+    /// Send a `PVEAPIToken` header with every request; no ticket needed.
+    ApiToken { id: String, secret: String },
+}
+
+/// A guest as listed in `/cluster/resources`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ClusterGuest {
+    pub vmid: u64,
+    pub node: String,
+    #[serde(rename = "type")]
+    pub kind: config::GuestKind,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Semicolon-separated, like `prod;soft-watchdog`.
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub template: u8,
+}
+
+impl ClusterGuest {
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags
+            .as_deref()
+            .unwrap_or_default()
+            .split([';', ',', ' '])
+            .filter(|tag| !tag.is_empty())
+    }
+}
+
+/// What the hypervisor reports about a guest in `status/current`.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestStatus {
+    pub running: bool,
+
+    /// Seconds since the guest's QEMU process or container was started.
+    /// Note that a QEMU `status/reset` does not restart the process,
+    /// so this keeps counting across hard resets.
+    pub uptime: u64,
+}
+
+/// How many lines from the end of a task's log to keep.
+const TASK_LOG_TAIL_LINES: usize = 10;
+
+/// How a Proxmox task ended.
+#[derive(Debug, Clone)]
+pub struct TaskOutcome {
+    pub upid: String,
+
+    /// `OK` on success, or the error message.
+    /// `None` if the task was still running when we stopped waiting.
+    pub exit_status: Option<String>,
+
+    pub log_tail: Vec<String>,
+}
+
+impl TaskOutcome {
+    pub fn succeeded(&self) -> bool {
+        // Tasks that finished with warnings still did their job.
+        matches!(&self.exit_status, Some(status) if status == "OK" || status.starts_with("WARNINGS"))
+    }
+}
+
+impl std::fmt::Display for TaskOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.exit_status {
+            Some(status) => write!(f, "{} finished with status: {}", self.upid, status)?,
+            None => write!(f, "{} did not finish in time", self.upid)?,
+        }
+        if !self.log_tail.is_empty() {
+            write!(f, "\n\n{}", self.log_tail.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
 struct MyRetryableStrategy;
 
 impl RetryableStrategy for MyRetryableStrategy {
@@ -42,7 +219,7 @@ impl RetryableStrategy for MyRetryableStrategy {
 }
 
 impl Api {
-    pub fn from_config(conf: &config::ProxmoxAuth) -> Self {
+    pub fn from_config(conf: &config::ProxmoxAuth) -> Result<Self, Error> {
         let retry_policy = ExponentialBackoff::builder()
             .retry_bounds(
                 std::time::Duration::from_millis(100),
@@ -50,11 +227,26 @@ impl Api {
             )
             .build_with_max_retries(3);
 
-        Self {
+        let credentials = match (&conf.api_token, &conf.user, &conf.password) {
+            (Some(token), _, _) => Credentials::ApiToken {
+                id: token.id.clone(),
+                secret: token.secret.clone(),
+            },
+            (None, Some(user), Some(password)) => Credentials::Password {
+                username: user.clone(),
+                password: password.clone(),
+            },
+            (None, _, _) => {
+                return Err(Error::Config(
+                    "needs either api_token or user and password".to_string(),
+                ));
+            }
+        };
+
+        Ok(Self {
             inner: Arc::new(Inner {
                 base_url: conf.url.clone(),
-                username: conf.user.clone(),
-                password: conf.password.clone(),
+                credentials,
                 ticket: Mutex::new(None),
                 csrf: Mutex::new(None),
                 ticket_expiry: Mutex::new(std::time::Instant::now()),
@@ -70,11 +262,22 @@ impl Api {
                 MyRetryableStrategy,
             ))
             .build(),
+        })
+    }
+
+    /// Make sure we can authenticate before starting any monitoring.
+    /// API tokens are sent as-is, so there is nothing to do for them.
+    pub async fn login(&self) -> Result<(), Error> {
+        if let Credentials::Password { username, password } = &self.inner.credentials {
+            self.get_ticket(username, password).await?;
         }
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self), level = "debug")]
-    pub async fn get_ticket(&self) -> (String, String) {
+    /// Only password credentials have a ticket,
+    /// so callers pass them in after matching on [`Credentials::Password`].
+    #[tracing::instrument(skip(self, password), level = "debug")]
+    async fn get_ticket(&self, username: &str, password: &str) -> Result<(String, String), Error> {
         // If there is a cached ticket and it hasn't yet expired,
         // return it.
         let ticket_expiry = *self.inner.ticket_expiry.lock().unwrap();
@@ -82,7 +285,7 @@ impl Api {
             let ticket = self.inner.ticket.lock().unwrap().clone().unwrap();
             let csrf = self.inner.csrf.lock().unwrap().clone().unwrap();
             tracing::debug!("Reusing cached ticket");
-            return (ticket, csrf);
+            return Ok((ticket, csrf));
         }
 
         // Copy the inner ticket,
@@ -96,14 +299,13 @@ impl Api {
                 .bearer_auth(&ticket)
                 .send()
                 .await
+                && res.status().is_success()
             {
-                if res.status().is_success() {
-                    tracing::debug!("Cached ticket is still valid");
-                    let csrf = self.inner.csrf.lock().unwrap().clone().unwrap();
-                    *self.inner.ticket_expiry.lock().unwrap() =
-                        std::time::Instant::now() + std::time::Duration::from_secs(60);
-                    return (ticket, csrf);
-                }
+                tracing::debug!("Cached ticket is still valid");
+                let csrf = self.inner.csrf.lock().unwrap().clone().unwrap();
+                *self.inner.ticket_expiry.lock().unwrap() =
+                    std::time::Instant::now() + std::time::Duration::from_secs(60);
+                return Ok((ticket, csrf));
             }
         }
 
@@ -114,28 +316,37 @@ impl Api {
             .client
             .post(format!("{}/api2/json/access/ticket", self.inner.base_url))
             .json(&serde_json::json!({
-                "username": self.inner.username,
-                "password": self.inner.password,
+                "username": username,
+                "password": password,
             }))
             .send()
-            .await
-            .unwrap();
-
-        if res.status().is_success() {
-            let json: serde_json::Value = res.json().await.unwrap();
-            let ticket = json["data"]["ticket"].as_str().unwrap().to_string();
-            let csrf = json["data"]["CSRFPreventionToken"]
-                .as_str()
-                .unwrap()
-                .to_string();
-            self.inner.ticket.lock().unwrap().replace(ticket.clone());
-            self.inner.csrf.lock().unwrap().replace(csrf.clone());
-            *self.inner.ticket_expiry.lock().unwrap() =
-                std::time::Instant::now() + std::time::Duration::from_secs(10 * 60);
-            return (ticket, csrf);
-        } else {
-            panic!("failed to get ticket: {}", res.status());
-        }
+            .await?;
+
+        // A failed login is always an auth problem,
+        // whatever status Proxmox chose to report it with.
+        let res = match check_status(res).await {
+            Ok(res) => res,
+            Err(Error::Status { status, message }) => {
+                return Err(Error::Auth(format!("{status}: {message}")));
+            }
+            Err(why) => return Err(why),
+        };
+
+        let data = response_data(res).await?;
+        let (Some(ticket), Some(csrf)) = (
+            data["ticket"].as_str(),
+            data["CSRFPreventionToken"].as_str(),
+        ) else {
+            return Err(Error::Malformed(format!(
+                "login response has no ticket: {data}"
+            )));
+        };
+        let (ticket, csrf) = (ticket.to_string(), csrf.to_string());
+        self.inner.ticket.lock().unwrap().replace(ticket.clone());
+        self.inner.csrf.lock().unwrap().replace(csrf.clone());
+        *self.inner.ticket_expiry.lock().unwrap() =
+            std::time::Instant::now() + std::time::Duration::from_secs(10 * 60);
+        Ok((ticket, csrf))
     }
 
     #[tracing::instrument(name = "ticketed_request", skip(self), level = "debug")]
@@ -143,29 +354,119 @@ impl Api {
         &self,
         method: reqwest::Method,
         path: &str,
-    ) -> reqwest_middleware::RequestBuilder {
+    ) -> Result<reqwest_middleware::RequestBuilder, Error> {
         let url = format!("{}/api2/json{}", self.inner.base_url, path);
-        let (ticket, csrf) = self.get_ticket().await;
-        self.client
-            .request(method, url)
-            .bearer_auth(format!("PVEAuthCookie={ticket}"))
-            .header("CSRFPreventionToken", csrf)
+        Ok(match &self.inner.credentials {
+            Credentials::ApiToken { id, secret } => self
+                .client
+                .request(method, url)
+                .header("Authorization", format!("PVEAPIToken={id}={secret}")),
+            Credentials::Password { username, password } => {
+                let (ticket, csrf) = self.get_ticket(username, password).await?;
+                self.client
+                    .request(method, url)
+                    .bearer_auth(format!("PVEAuthCookie={ticket}"))
+                    .header("CSRFPreventionToken", csrf)
+            }
+        })
+    }
+
+    /// The API path of the guest, like `/nodes/pve/qemu/100`.
+    fn guest_path(config: &config::VmConfig) -> Result<String, Error> {
+        let Some(node) = &config.node else {
+            return Err(Error::NotFound(format!(
+                "node of guest {} is not known yet",
+                config.vmid
+            )));
+        };
+        Ok(format!(
+            "/nodes/{}/{}/{}",
+            node,
+            config.kind.api_segment(),
+            config.vmid
+        ))
+    }
+
+    /// List all QEMU VMs and LXC containers in the cluster.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn list_guests(&self) -> Result<Vec<ClusterGuest>, Error> {
+        tracing::debug!("Listing cluster resources");
+        let res = self
+            .ticketed_request(reqwest::Method::GET, "/cluster/resources")
+            .await?
+            .query(&[("type", "vm")])
+            .send()
+            .await?;
+
+        let data = response_data(check_status(res).await?).await?;
+        serde_json::from_value(data).map_err(|why| Error::Malformed(why.to_string()))
     }
 
+    /// Find which node the guest currently lives on.
     #[tracing::instrument(skip(self, config))]
-    pub async fn ping_guest_agent(&self, config: &config::VmConfig) -> Result<(), ReqError> {
+    pub async fn find_guest_node(&self, config: &config::VmConfig) -> Result<String, Error> {
+        tracing::debug!("Looking up guest in cluster resources");
+        self.list_guests()
+            .await?
+            .into_iter()
+            .find(|guest| guest.vmid.to_string() == config.vmid && guest.kind == config.kind)
+            .map(|guest| guest.node)
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "guest {} is not in the cluster resources",
+                    config.vmid
+                ))
+            })
+    }
+
+    /// Get the guest's configuration, like `qm config` would show it.
+    pub async fn get_guest_config(
+        &self,
+        config: &config::VmConfig,
+    ) -> Result<serde_json::Value, Error> {
+        self.get_guest_json(config, "config").await
+    }
+
+    /// GET one of the guest's endpoints, like `status/current` or `agent/get-osinfo`,
+    /// and return its raw `data`.
+    #[tracing::instrument(skip(self, config))]
+    pub async fn get_guest_json(
+        &self,
+        config: &config::VmConfig,
+        endpoint: &str,
+    ) -> Result<serde_json::Value, Error> {
+        tracing::debug!("Getting guest {}", endpoint);
+        let res = self
+            .ticketed_request(
+                reqwest::Method::GET,
+                &format!("{}/{}", Self::guest_path(config)?, endpoint),
+            )
+            .await?
+            .send()
+            .await?;
+
+        response_data(check_status(res).await?).await
+    }
+
+    #[tracing::instrument(skip(self, config))]
+    pub async fn ping_guest_agent(&self, config: &config::VmConfig) -> Result<(), Error> {
+        if config.kind == config::GuestKind::Lxc {
+            tracing::debug!("Pinging container with pct exec");
+            return pct::ping(&config.vmid).await;
+        }
+
         tracing::debug!("Pinging guest agent");
         let res = self
             .ticketed_request(
                 reqwest::Method::POST,
-                &format!("/nodes/{}/qemu/{}/agent/ping", config.node, config.vmid),
+                &format!("{}/agent/ping", Self::guest_path(config)?),
             )
-            .await
+            .await?
             .send()
             .await?;
 
         // println!("VMID {} ping: {}", config.vmid, res.text().await?);
-        res.error_for_status()?;
+        check_status(res).await?;
         Ok(())
     }
 
@@ -175,18 +476,20 @@ impl Api {
         config: &config::VmConfig,
         path: &str,
         content: &[u8],
-    ) -> Result<(), ReqError> {
+    ) -> Result<(), Error> {
+        if config.kind == config::GuestKind::Lxc {
+            tracing::debug!("Writing container file {}", path);
+            return pct::write_file(&config.vmid, path, content).await;
+        }
+
         tracing::debug!("Writing guest agent file {}", path);
         let content = base64::engine::general_purpose::STANDARD.encode(content);
         let res = self
             .ticketed_request(
                 reqwest::Method::POST,
-                &format!(
-                    "/nodes/{}/qemu/{}/agent/file-write",
-                    config.node, config.vmid
-                ),
+                &format!("{}/agent/file-write", Self::guest_path(config)?),
             )
-            .await
+            .await?
             .json(&serde_json::json!({
                 "file": path,
                 "content": content,
@@ -195,7 +498,7 @@ impl Api {
             .send()
             .await?;
 
-        res.error_for_status()?.text().await?;
+        check_status(res).await?.text().await?;
 
         Ok(())
     }
@@ -205,63 +508,337 @@ impl Api {
         &self,
         config: &config::VmConfig,
         path: &str,
-    ) -> Result<String, ReqError> {
+    ) -> Result<String, Error> {
+        if config.kind == config::GuestKind::Lxc {
+            tracing::debug!("Reading container file {}", path);
+            return pct::read_file(&config.vmid, path).await;
+        }
+
         tracing::debug!("Reading guest agent file {}", path);
         let res = self
             .ticketed_request(
                 reqwest::Method::GET,
-                &format!(
-                    "/nodes/{}/qemu/{}/agent/file-read",
-                    config.node, config.vmid
-                ),
+                &format!("{}/agent/file-read", Self::guest_path(config)?),
             )
-            .await
+            .await?
             .query(&[("file", path)])
             .send()
             .await?;
 
-        let res = res.error_for_status()?;
-        let json: serde_json::Value = res.json().await.unwrap();
-        let content = json["data"]["content"].as_str().unwrap();
+        let data = response_data(check_status(res).await?).await?;
+        let Some(content) = data["content"].as_str() else {
+            return Err(Error::Malformed(format!(
+                "file-read response has no content: {data}"
+            )));
+        };
 
         Ok(content.to_string())
     }
 
     #[tracing::instrument(skip(self, config))]
-    pub async fn get_is_machine_running(
-        &self,
-        config: &config::VmConfig,
-    ) -> Result<bool, ReqError> {
+    pub async fn get_guest_status(&self, config: &config::VmConfig) -> Result<GuestStatus, Error> {
         tracing::debug!("Getting VM status from hypervisor");
         let res = self
             .ticketed_request(
                 reqwest::Method::GET,
-                &format!("/nodes/{}/qemu/{}/status/current", config.node, config.vmid),
+                &format!("{}/status/current", Self::guest_path(config)?),
+            )
+            .await?
+            .send()
+            .await?;
+
+        let data = response_data(check_status(res).await?).await?;
+        let Some(status) = data["status"].as_str() else {
+            return Err(Error::Malformed(format!(
+                "status/current response has no status: {data}"
+            )));
+        };
+        Ok(GuestStatus {
+            running: status == "running",
+            uptime: data["uptime"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    /// Run a command inside the guest and return its stdout.
+    /// Uses the guest agent for VMs, and `pct exec` for containers.
+    #[tracing::instrument(skip(self, config))]
+    pub async fn guest_exec(
+        &self,
+        config: &config::VmConfig,
+        command: &[&str],
+        timeout: std::time::Duration,
+    ) -> Result<String, Error> {
+        if config.kind == config::GuestKind::Lxc {
+            let stdout = pct::exec(&config.vmid, command, None, timeout).await?;
+            return Ok(String::from_utf8_lossy(&stdout).into_owned());
+        }
+
+        let res = self
+            .ticketed_request(
+                reqwest::Method::POST,
+                &format!("{}/agent/exec", Self::guest_path(config)?),
+            )
+            .await?
+            .json(&serde_json::json!({ "command": command }))
+            .send()
+            .await?;
+        let data = response_data(check_status(res).await?).await?;
+        let Some(pid) = data["pid"].as_u64() else {
+            return Err(Error::Malformed(format!(
+                "agent/exec response has no pid: {data}"
+            )));
+        };
+
+        // The command runs in the background, so poll until it exits.
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let res = self
+                .ticketed_request(
+                    reqwest::Method::GET,
+                    &format!("{}/agent/exec-status", Self::guest_path(config)?),
+                )
+                .await?
+                .query(&[("pid", pid)])
+                .send()
+                .await?;
+            let data = response_data(check_status(res).await?).await?;
+
+            if data["exited"].as_u64() == Some(1) || data["exited"].as_bool() == Some(true) {
+                let stdout = data["out-data"].as_str().unwrap_or_default().to_string();
+                return match data["exitcode"].as_i64() {
+                    Some(0) => Ok(stdout),
+                    exitcode => Err(Error::CommandFailed(format!(
+                        "exit code {:?}: {}",
+                        exitcode,
+                        data["err-data"].as_str().unwrap_or_default().trim()
+                    ))),
+                };
+            }
+            if std::time::Instant::now() >= deadline {
+                return Err(Error::AgentNotRunning(format!(
+                    "command did not finish in {} seconds",
+                    timeout.as_secs()
+                )));
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    }
+
+    /// POST to one of the guest's `status/*` endpoints, like `status/reset`.
+    /// Returns the UPID of the task Proxmox started for it.
+    async fn power_action(&self, config: &config::VmConfig, action: &str) -> Result<String, Error> {
+        let res = self
+            .ticketed_request(
+                reqwest::Method::POST,
+                &format!("{}/status/{}", Self::guest_path(config)?, action),
             )
-            .await
+            .await?
+            .send()
+            .await?;
+
+        let data = response_data(check_status(res).await?).await?;
+        let Some(upid) = data.as_str() else {
+            return Err(Error::Malformed(format!(
+                "status/{action} response has no task UPID: {data}"
+            )));
+        };
+
+        Ok(upid.to_string())
+    }
+
+    /// Look at the task once.
+    /// Returns how it ended once it has finished, or right away if `give_up` is set,
+    /// and `None` while it is still running.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn check_task(
+        &self,
+        upid: &str,
+        give_up: bool,
+    ) -> Result<Option<TaskOutcome>, Error> {
+        // UPIDs look like `UPID:node:pid:pstart:starttime:type:id:user:`,
+        // and the task can only be queried on the node that runs it.
+        let Some(node) = upid.split(':').nth(1) else {
+            return Err(Error::Malformed(format!("invalid UPID: {upid}")));
+        };
+        let task_path = format!("/nodes/{node}/tasks/{upid}");
+
+        let res = self
+            .ticketed_request(reqwest::Method::GET, &format!("{task_path}/status"))
+            .await?
             .send()
+            .await?;
+        let data = response_data(check_status(res).await?).await?;
+        let exit_status = if data["status"].as_str() == Some("stopped") {
+            Some(data["exitstatus"].as_str().unwrap_or("unknown").to_string())
+        } else if give_up {
+            None
+        } else {
+            return Ok(None);
+        };
+
+        let res = self
+            .ticketed_request(reqwest::Method::GET, &format!("{task_path}/log"))
             .await?
-            .error_for_status()?;
+            .query(&[("limit", "1000")])
+            .send()
+            .await?;
+        let data = response_data(check_status(res).await?).await?;
+        let lines: Vec<String> = data
+            .as_array()
+            .map(|lines| {
+                lines
+                    .iter()
+                    .filter_map(|line| line["t"].as_str().map(|t| t.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let log_tail = lines[lines.len().saturating_sub(TASK_LOG_TAIL_LINES)..].to_vec();
 
-        let json: serde_json::Value = res.json().await.expect("failed to parse response as JSON");
-        let status = json["data"]["status"].as_str().unwrap();
-        Ok(status == "running")
+        Ok(Some(TaskOutcome {
+            upid: upid.to_string(),
+            exit_status,
+            log_tail,
+        }))
     }
 
+    /// Save the VM's display into a PNG file on the node it runs on.
+    /// Proxmox only lets root@pam run monitor commands other than `info`,
+    /// so this fails when logged in any other way, like with an API token.
     #[tracing::instrument(skip(self, config))]
-    pub async fn reset_vm(&self, config: &config::VmConfig) -> Result<(), ReqError> {
+    pub async fn screendump(&self, config: &config::VmConfig, path: &str) -> Result<(), Error> {
+        // The monitor splits the command on spaces, and has quoting of its own,
+        // so a path that needs either would be cut short or misread.
+        if path.contains(|c: char| c.is_whitespace() || c.is_control() || "\"'\\".contains(c)) {
+            return Err(Error::CommandFailed(format!(
+                "cannot take a screenshot into {path:?}, the monitor can't take paths with spaces or quotes"
+            )));
+        }
+
+        tracing::debug!("Taking screenshot of VM console");
+        let res = self
+            .ticketed_request(
+                reqwest::Method::POST,
+                &format!("{}/monitor", Self::guest_path(config)?),
+            )
+            .await?
+            .json(&serde_json::json!({ "command": format!("screendump {path} -f png") }))
+            .send()
+            .await?;
+
+        // The monitor answers with the command's output,
+        // which is empty unless something went wrong.
+        let data = response_data(check_status(res).await?).await?;
+        match data.as_str().map(str::trim) {
+            None | Some("") => Ok(()),
+            Some(output) => Err(Error::CommandFailed(output.to_string())),
+        }
+    }
+
+    /// Returns the UPID of the reset task.
+    #[tracing::instrument(skip(self, config))]
+    pub async fn reset_vm(&self, config: &config::VmConfig) -> Result<String, Error> {
+        if config.kind == config::GuestKind::Lxc {
+            return Err(Error::CommandFailed(
+                "containers can't be reset, only stopped and started".to_string(),
+            ));
+        }
+
         tracing::info!("Resetting VM in hypervisor");
+        self.power_action(config, "reset").await
+    }
+
+    /// Hard stop, like pulling the plug. Returns the UPID of the stop task.
+    #[tracing::instrument(skip(self, config))]
+    pub async fn stop_vm(&self, config: &config::VmConfig) -> Result<String, Error> {
+        tracing::info!("Stopping guest in hypervisor");
+        self.power_action(config, "stop").await
+    }
+
+    #[tracing::instrument(skip(self, config))]
+    pub async fn start_vm(&self, config: &config::VmConfig) -> Result<String, Error> {
+        tracing::info!("Starting guest in hypervisor");
+        self.power_action(config, "start").await
+    }
+
+    /// Press the virtual power button, or ask the container's init to shut down.
+    #[tracing::instrument(skip(self, config))]
+    pub async fn shutdown_vm(&self, config: &config::VmConfig) -> Result<String, Error> {
+        tracing::info!("Shutting down guest in hypervisor");
+        self.power_action(config, "shutdown").await
+    }
+
+    #[tracing::instrument(skip(self, config))]
+    pub async fn guest_agent_shutdown(&self, config: &config::VmConfig) -> Result<(), Error> {
+        if config.kind == config::GuestKind::Lxc {
+            return Err(Error::CommandFailed(
+                "containers have no guest agent to shut down through".to_string(),
+            ));
+        }
+
+        tracing::info!("Shutting down guest through guest agent");
         let res = self
             .ticketed_request(
                 reqwest::Method::POST,
-                &format!("/nodes/{}/qemu/{}/status/reset", config.node, config.vmid),
+                &format!("{}/agent/shutdown", Self::guest_path(config)?),
             )
-            .await
+            .await?
             .send()
             .await?;
 
-        res.error_for_status()?;
+        check_status(res).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(user: Option<&str>, password: Option<&str>) -> config::ProxmoxAuth {
+        config::ProxmoxAuth {
+            url: "https://pve.example:8006".to_string(),
+            user: user.map(str::to_string),
+            password: password.map(str::to_string),
+            api_token: None,
+            allow_invalid_cert: false,
+        }
+    }
+
+    #[test]
+    fn needs_credentials() {
+        assert!(Api::from_config(&auth(Some("root@pam"), Some("secret"))).is_ok());
+        for auth in [
+            auth(None, None),
+            auth(Some("root@pam"), None),
+            auth(None, Some("secret")),
+        ] {
+            assert!(matches!(Api::from_config(&auth), Err(Error::Config(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn screendump_refuses_paths_the_monitor_would_misread() {
+        let api = Api::from_config(&auth(Some("root@pam"), Some("secret"))).unwrap();
+        let vm_config: config::VmConfig = serde_json::from_value(serde_json::json!({
+            "node": "pve1",
+            "vmid": "100",
+            "friendly_name": "web",
+            "max_no_warning_interval": 600,
+            "grace_period": 300,
+            "reset_duration": 120,
+        }))
+        .unwrap();
+
+        for path in [
+            "/var/lib/soft watchdog/screen.png",
+            "/tmp/screen.png -f ppm",
+            "/tmp/\"screen\".png",
+        ] {
+            assert!(matches!(
+                api.screendump(&vm_config, path).await,
+                Err(Error::CommandFailed(_))
+            ));
+        }
+    }
+}
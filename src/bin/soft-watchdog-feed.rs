@@ -0,0 +1,493 @@
+//! Runs in the guest, usually once a minute from `watchdog-feed.timer`.
+//! Reads the time the watchdog wrote, runs the configured checks,
+//! and writes the heartbeat the watchdog reads back.
+
+use std::io::{Read, Write};
+
+use proxmox_soft_watchdog::heartbeat;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(default = "default_current_time_path")]
+    current_time_path: std::path::PathBuf,
+
+    #[serde(default = "default_reset_after_path")]
+    reset_after_path: std::path::PathBuf,
+
+    #[serde(default = "default_nonce_path")]
+    nonce_path: std::path::PathBuf,
+
+    /// A file with the `heartbeat_secret` the watchdog has for this guest.
+    /// If missing, heartbeats are not signed.
+    #[serde(default)]
+    secret_file: Option<std::path::PathBuf>,
+
+    /// How long after the watchdog's time the guest may be reset,
+    /// unless it writes again.
+    /// In seconds.
+    #[serde(default = "default_reset_after")]
+    reset_after: u64,
+
+    #[serde(default)]
+    checks: Vec<CheckConfig>,
+}
+
+fn default_current_time_path() -> std::path::PathBuf {
+    "/tmp/watchdog_current_unix_time".into()
+}
+
+fn default_reset_after_path() -> std::path::PathBuf {
+    "/tmp/watchdog_reset_after".into()
+}
+
+fn default_nonce_path() -> std::path::PathBuf {
+    "/tmp/watchdog_nonce".into()
+}
+
+fn default_reset_after() -> u64 {
+    90
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckConfig {
+    /// Used in the failure reason, like `database`.
+    name: String,
+
+    /// How long the check may take.
+    /// In seconds.
+    #[serde(default = "default_check_timeout")]
+    timeout: u64,
+
+    #[serde(flatten)]
+    kind: CheckKind,
+}
+
+fn default_check_timeout() -> u64 {
+    10
+}
+
+/// How much of a failing command's output goes into the reason.
+const MAX_OUTPUT: usize = 4096;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CheckKind {
+    /// The systemd unit is active.
+    Systemd { unit: String },
+
+    /// Something accepts connections on the address, like `127.0.0.1:5432`.
+    Tcp { address: String },
+
+    /// The command exits successfully.
+    Command { command: Vec<String> },
+
+    /// The file was modified within the last `max_age` seconds,
+    /// like a backup job's stamp file.
+    FileFresh {
+        path: std::path::PathBuf,
+        max_age: u64,
+    },
+}
+
+fn main() -> std::process::ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let file_name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/etc/soft-watchdog-feed.json".to_string());
+    let config_text = match std::fs::read_to_string(&file_name) {
+        Ok(config_text) => config_text,
+        Err(why) => {
+            tracing::error!("Cannot read the config from {}: {}", file_name, why);
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    let config: Config = match serde_json::from_str(&config_text) {
+        Ok(config) => config,
+        Err(why) => {
+            tracing::error!("Cannot parse the config in {}: {}", file_name, why);
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+
+    match feed(&config) {
+        Ok(beat) if beat.status == heartbeat::Status::Ok => std::process::ExitCode::SUCCESS,
+        Ok(_) => std::process::ExitCode::FAILURE,
+        Err(why) => {
+            tracing::error!("{}", why);
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run the checks and write the heartbeat for them.
+fn feed(config: &Config) -> Result<heartbeat::Heartbeat, String> {
+    // Without the watchdog's time there's nothing to count from,
+    // and writing nothing makes the watchdog notice that something is wrong.
+    let current_time = std::fs::read_to_string(&config.current_time_path)
+        .map_err(|why| {
+            format!(
+                "Cannot read the time from {}: {}",
+                config.current_time_path.display(),
+                why
+            )
+        })?
+        .trim()
+        .parse::<u64>()
+        .map_err(|why| {
+            format!(
+                "Cannot parse the time in {}: {}",
+                config.current_time_path.display(),
+                why
+            )
+        })?;
+
+    let checks: Vec<heartbeat::Check> = config.checks.iter().map(run_check).collect();
+    let failing = checks
+        .iter()
+        .any(|check| check.status == heartbeat::Status::Failing);
+    let mut beat = heartbeat::Heartbeat {
+        reset_after: (!failing).then_some(current_time + config.reset_after),
+        status: if failing {
+            heartbeat::Status::Failing
+        } else {
+            heartbeat::Status::Ok
+        },
+        reason: None,
+        checks,
+        nonce: None,
+        signature: None,
+    };
+    if let Some(reason) = beat.reason() {
+        tracing::warn!("Reporting a problem: {}", reason);
+    }
+
+    if let Some(secret_file) = &config.secret_file {
+        let secret = std::fs::read_to_string(secret_file)
+            .and_then(|secret| {
+                let nonce = std::fs::read_to_string(&config.nonce_path)?;
+                Ok((secret, nonce))
+            })
+            .map_err(|why| format!("Cannot read the secret or the nonce: {why}"));
+        let (secret, nonce) = secret?;
+        let nonce = nonce.trim().to_string();
+        beat.signature = Some(heartbeat::sign(secret.as_bytes(), &nonce, beat.reset_after));
+        beat.nonce = Some(nonce);
+    }
+
+    let content = serde_json::to_vec(&beat).expect("heartbeat is serializable");
+    write_atomically(&config.reset_after_path, &content).map_err(|why| {
+        format!(
+            "Cannot write the heartbeat into {}: {}",
+            config.reset_after_path.display(),
+            why
+        )
+    })?;
+    Ok(beat)
+}
+
+fn run_check(check: &CheckConfig) -> heartbeat::Check {
+    let timeout = std::time::Duration::from_secs(check.timeout);
+    let result = match &check.kind {
+        CheckKind::Systemd { unit } => {
+            // `is-active` prints why the unit isn't, like `inactive` or `failed`.
+            run(&["systemctl", "is-active", unit.as_str()], timeout)
+        }
+        CheckKind::Tcp { address } => connect(address, timeout),
+        CheckKind::Command { command } => {
+            let command: Vec<&str> = command.iter().map(String::as_str).collect();
+            run(&command, timeout)
+        }
+        CheckKind::FileFresh { path, max_age } => file_age(path).and_then(|age| {
+            if age > *max_age {
+                Err(format!("last modified {age} seconds ago"))
+            } else {
+                Ok(())
+            }
+        }),
+    };
+
+    match result {
+        Ok(()) => heartbeat::Check {
+            name: check.name.clone(),
+            status: heartbeat::Status::Ok,
+            reason: None,
+        },
+        Err(reason) => heartbeat::Check {
+            name: check.name.clone(),
+            status: heartbeat::Status::Failing,
+            reason: Some(reason),
+        },
+    }
+}
+
+/// Run the command, and give up on it after the timeout.
+/// Failures are described by the command's own output, if it has any.
+fn run(command: &[&str], timeout: std::time::Duration) -> Result<(), String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err("empty command".to_string());
+    };
+    let mut child = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|why| format!("cannot run {program}: {why}"))?;
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = std::time::Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if std::time::Instant::now() < deadline => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "{program} did not finish in {} seconds",
+                    timeout.as_secs()
+                ));
+            }
+            Err(why) => return Err(format!("cannot wait for {program}: {why}")),
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+
+    let output = [stdout, stderr]
+        .into_iter()
+        .map(|stream| String::from_utf8_lossy(&stream.finish()).trim().to_string())
+        .find(|stream| !stream.is_empty());
+    Err(output.unwrap_or_else(|| status.to_string()))
+}
+
+fn connect(address: &str, timeout: std::time::Duration) -> Result<(), String> {
+    let addresses = std::net::ToSocketAddrs::to_socket_addrs(address)
+        .map_err(|why| format!("cannot resolve {address}: {why}"))?;
+
+    let mut last_error = format!("{address} did not resolve to anything");
+    for socket_address in addresses {
+        match std::net::TcpStream::connect_timeout(&socket_address, timeout) {
+            Ok(_) => return Ok(()),
+            Err(why) => last_error = format!("cannot connect to {socket_address}: {why}"),
+        }
+    }
+    Err(last_error)
+}
+
+/// How long ago the file was modified, in seconds.
+fn file_age(path: &std::path::Path) -> Result<u64, String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|why| format!("cannot stat {}: {}", path.display(), why))?;
+    Ok(modified.elapsed().unwrap_or_default().as_secs())
+}
+
+/// Write to a temporary file first, so the watchdog never reads half a heartbeat.
+fn write_atomically(path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// A stream of the command that is read in the background,
+/// so that the command never blocks on a full pipe.
+struct Drain {
+    /// The start of the stream.
+    kept: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+fn drain(stream: Option<impl Read + Send + 'static>) -> Drain {
+    let kept = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let thread = {
+        let kept = kept.clone();
+        std::thread::spawn(move || {
+            let Some(mut stream) = stream else {
+                return;
+            };
+            let mut buffer = [0; 4096];
+            while let Ok(read @ 1..) = stream.read(&mut buffer) {
+                let mut kept = kept.lock().unwrap();
+                let room = MAX_OUTPUT.saturating_sub(kept.len());
+                kept.extend_from_slice(&buffer[..read.min(room)]);
+            }
+        })
+    };
+    Drain { kept, thread }
+}
+
+impl Drain {
+    /// What was read by the end of the stream.
+    /// Something the command started in the background may keep the pipe open,
+    /// so after a second it's whatever was read by then.
+    fn finish(self) -> Vec<u8> {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        while !self.thread.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::mem::take(&mut *self.kept.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// A config with its files in a fresh directory, and the watchdog's time written.
+    fn config(name: &str, checks: serde_json::Value) -> Config {
+        let dir = std::env::temp_dir().join(format!(
+            "soft-watchdog-feed-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("current_time"), "1000\n").unwrap();
+        serde_json::from_value(serde_json::json!({
+            "current_time_path": dir.join("current_time"),
+            "reset_after_path": dir.join("reset_after"),
+            "nonce_path": dir.join("nonce"),
+            "checks": checks,
+        }))
+        .unwrap()
+    }
+
+    fn written(config: &Config) -> heartbeat::Heartbeat {
+        let text = std::fs::read_to_string(&config.reset_after_path).unwrap();
+        heartbeat::Heartbeat::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn run_succeeds_quietly() {
+        assert_eq!(run(&["true"], SECOND), Ok(()));
+    }
+
+    #[test]
+    fn run_reports_the_output_of_a_failure() {
+        assert_eq!(
+            run(&["sh", "-c", "echo database is down >&2; exit 3"], SECOND),
+            Err("database is down".to_string())
+        );
+        assert_eq!(run(&["false"], SECOND), Err("exit status: 1".to_string()));
+        assert!(
+            run(&["/nonexistent/check"], SECOND)
+                .unwrap_err()
+                .starts_with("cannot run /nonexistent/check")
+        );
+        assert_eq!(run(&[], SECOND), Err("empty command".to_string()));
+    }
+
+    #[test]
+    fn run_gives_up_after_the_timeout() {
+        let started = std::time::Instant::now();
+        assert_eq!(
+            run(&["sleep", "10"], SECOND),
+            Err("sleep did not finish in 1 seconds".to_string())
+        );
+        assert!(started.elapsed() < 5 * SECOND);
+    }
+
+    #[test]
+    fn run_keeps_only_the_start_of_chatty_output() {
+        // Far more than a pipe holds, so the command would block if nothing read it.
+        let reason = run(&["sh", "-c", "yes | head -c 1000000; exit 1"], 5 * SECOND).unwrap_err();
+        assert!(reason.len() <= MAX_OUTPUT);
+        assert!(reason.starts_with("y\ny\n"));
+    }
+
+    #[test]
+    fn writes_a_heartbeat_when_the_checks_pass() {
+        let config = config(
+            "pass",
+            serde_json::json!([{"name": "shell", "type": "command", "command": ["true"]}]),
+        );
+        let beat = feed(&config).unwrap();
+        assert_eq!(beat.status, heartbeat::Status::Ok);
+        assert_eq!(beat.reset_after, Some(1000 + default_reset_after()));
+
+        let written = written(&config);
+        assert_eq!(written.status, heartbeat::Status::Ok);
+        assert_eq!(written.reset_after, Some(1000 + default_reset_after()));
+        assert_eq!(written.checks.len(), 1);
+        assert_eq!(written.reason(), None);
+        assert_eq!(written.signature, None);
+    }
+
+    #[test]
+    fn writes_the_reason_when_a_check_fails() {
+        let config = config(
+            "fail",
+            serde_json::json!([
+                {"name": "shell", "type": "command", "command": ["true"]},
+                {"name": "database", "type": "command", "command": ["sh", "-c", "echo down; exit 1"]},
+            ]),
+        );
+        let beat = feed(&config).unwrap();
+        assert_eq!(beat.status, heartbeat::Status::Failing);
+
+        let written = written(&config);
+        assert_eq!(written.status, heartbeat::Status::Failing);
+        assert_eq!(written.reset_after, None);
+        assert!(written.reason().unwrap().contains("database"));
+        assert!(written.reason().unwrap().contains("down"));
+    }
+
+    #[test]
+    fn signs_the_heartbeat_with_the_nonce() {
+        let mut config = config("signed", serde_json::json!([]));
+        let dir = config.reset_after_path.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join("secret"), "hunter2\n").unwrap();
+        std::fs::write(&config.nonce_path, "abc123\n").unwrap();
+        config.secret_file = Some(dir.join("secret"));
+        feed(&config).unwrap();
+
+        let written = written(&config);
+        assert_eq!(written.nonce.as_deref(), Some("abc123"));
+        assert!(
+            written
+                .verify(b"hunter2", |nonce| nonce == "abc123")
+                .is_ok()
+        );
+        assert!(
+            written
+                .verify(b"hunter3", |nonce| nonce == "abc123")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn writes_nothing_without_the_watchdogs_time() {
+        let config = config("no-time", serde_json::json!([]));
+        std::fs::remove_file(&config.current_time_path).unwrap();
+        assert!(
+            feed(&config)
+                .unwrap_err()
+                .starts_with("Cannot read the time")
+        );
+        assert!(!config.reset_after_path.exists());
+    }
+
+    #[test]
+    fn writes_nothing_without_the_nonce() {
+        let mut config = config("no-nonce", serde_json::json!([]));
+        let dir = config.reset_after_path.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join("secret"), "hunter2").unwrap();
+        config.secret_file = Some(dir.join("secret"));
+        assert!(feed(&config).is_err());
+        assert!(!config.reset_after_path.exists());
+    }
+}
@@ -1,27 +1,332 @@
 use serde::{Deserialize, Serialize};
 
+use crate::messages;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub proxmox_auth: ProxmoxAuth,
+
+    #[serde(default)]
     pub vm_configs: Vec<VmConfig>,
+
+    /// If set, guests with a tag are monitored too,
+    /// without being listed in `vm_configs`.
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+
+    /// Where to send messages about the guests.
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+
+    /// Like `NotifierConfig.language`, for the Telegram shorthand in `VmConfig`,
+    /// which has no notifier config of its own.
+    #[serde(default)]
+    pub language: Language,
+
+    /// Like `NotifierConfig.templates`, for the Telegram shorthand in `VmConfig`.
+    #[serde(default)]
+    pub templates: std::collections::BTreeMap<EventKind, String>,
+
+    /// If set, the watchdog takes commands like `/snooze` from Telegram.
+    #[serde(default)]
+    pub telegram_bot: Option<TelegramBotConfig>,
+
+    /// If set, messages are queued on disk and retried until they are delivered,
+    /// instead of being lost when a notifier is down.
+    #[serde(default)]
+    pub outbox: Option<OutboxConfig>,
+
+    /// If set, messages that arrive close together are sent as one digest per notifier.
+    #[serde(default)]
+    pub digest: Option<DigestConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestConfig {
+    /// How long to collect messages after the first one, before sending the digest.
+    /// In seconds.
+    #[serde(default = "default_digest_window")]
+    pub window: u64,
+
+    /// Send critical messages, like resets, right away instead of waiting for the digest.
+    #[serde(default = "default_bypass_critical")]
+    pub bypass_critical: bool,
+}
+
+fn default_digest_window() -> u64 {
+    30
+}
+
+fn default_bypass_critical() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxConfig {
+    /// Where queued messages are kept.
+    /// Messages that could not be delivered in time are moved into `failed` inside it.
+    pub dir: std::path::PathBuf,
+
+    /// How long to keep retrying a message before giving up on it.
+    /// In seconds. Messages the service rejects outright, like with a 400,
+    /// are given up on right away.
+    #[serde(default = "default_outbox_max_age")]
+    pub max_age: u64,
+
+    /// If set, delivery counters are written here in the Prometheus text format,
+    /// like `/var/lib/prometheus/node-exporter/soft_watchdog.prom`.
+    #[serde(default)]
+    pub metrics_file: Option<std::path::PathBuf>,
+}
+
+fn default_outbox_max_age() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelegramBotConfig {
+    pub bot_token: String,
+
+    /// Commands from any other chat are ignored.
+    pub authorized_chat_ids: Vec<i64>,
+
+    #[serde(default = "default_telegram_api_url")]
+    pub api_url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifierConfig {
+    /// Used to pick this notifier in `VmConfig.notifiers`.
+    pub name: String,
+
+    /// The language of the bundled messages.
+    #[serde(default)]
+    pub language: Language,
+
+    /// Handlebars templates for the full text of each kind of event,
+    /// like `"reset": "🚨 {{friendly_name}} on {{node}}: {{text}}"`.
+    /// Kinds without a template get `{{subject}}: {{text}}`.
+    /// Backends that show the subject on its own, like email, use the template as the body.
+    #[serde(default)]
+    pub templates: std::collections::BTreeMap<EventKind, String>,
+
+    /// While these are on, only critical messages are sent right away.
+    /// The rest are held, and sent as one digest when the quiet hours end.
+    /// Ignored by `pagerduty` and `alertmanager`, which have schedules of their own.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+
+    #[serde(flatten)]
+    pub backend: NotifierBackend,
+}
+
+/// Like `22:00` to `07:00`, in the local time of the watchdog host.
+/// May span midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Ru,
+}
+
+/// Every URL and host is configurable,
+/// so each backend can be pointed at a local stand-in server for testing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierBackend {
+    Telegram {
+        bot_token: String,
+        chat_id: String,
+        #[serde(default = "default_telegram_api_url")]
+        api_url: String,
+    },
+
+    /// POSTs the message as JSON to any URL.
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: std::collections::BTreeMap<String, String>,
+    },
+
+    Email {
+        smtp_host: String,
+        #[serde(default = "default_smtp_port")]
+        smtp_port: u16,
+        #[serde(default)]
+        smtp_security: SmtpSecurity,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+
+    Ntfy {
+        #[serde(default = "default_ntfy_url")]
+        url: String,
+        topic: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+
+    Gotify {
+        url: String,
+        token: String,
+        #[serde(default = "default_gotify_priority")]
+        priority: u8,
+    },
+
+    Matrix {
+        homeserver: String,
+        access_token: String,
+        room_id: String,
+    },
+
+    Slack {
+        webhook_url: String,
+    },
+
+    Discord {
+        webhook_url: String,
+    },
+
+    /// Opens a PagerDuty incident when a guest enters its grace period,
+    /// raises it to critical when the guest is reset, and resolves it when the guest is OK.
+    /// Other messages are ignored.
+    Pagerduty {
+        /// The integration key of an Events API v2 integration.
+        routing_key: String,
+        #[serde(default = "default_pagerduty_url")]
+        url: String,
+    },
+
+    /// Like `pagerduty`, but posts alerts to Alertmanager, like `http://localhost:9093`.
+    Alertmanager {
+        url: String,
+        /// Added to the labels of every alert, like `{"team": "infra"}`.
+        #[serde(default)]
+        labels: std::collections::BTreeMap<String, String>,
+        #[serde(default)]
+        headers: std::collections::BTreeMap<String, String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Plain SMTP, upgraded with STARTTLS.
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// No encryption at all; only for local relays and test servers.
+    None,
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_ntfy_url() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+fn default_gotify_priority() -> u8 {
+    5
+}
+
+fn default_pagerduty_url() -> String {
+    "https://events.pagerduty.com/v2/enqueue".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Guests with this Proxmox tag are monitored.
+    #[serde(default = "default_discovery_tag")]
+    pub tag: String,
+
+    /// How often to look for tagged guests.
+    /// In seconds.
+    #[serde(default = "default_discovery_interval")]
+    pub interval: u64,
+
+    /// Fields of `VmConfig` used for every discovered guest,
+    /// like `grace_period` or `telegram_chat_id`.
+    ///
+    /// Each guest can override them with extra tags like `soft-watchdog.grace_period.600`
+    /// or `soft-watchdog.dry_run`,
+    /// or with lines like `soft-watchdog.grace_period = 600` in its description.
+    /// Only the timings, `guest_os`, `dry_run` and `live_countdown` can be overridden;
+    /// a guest with any other override is not monitored,
+    /// or keeps its previous settings if it already is.
+    /// Changed overrides apply without starting the guest's monitoring over.
+    #[serde(default)]
+    pub defaults: serde_json::Map<String, serde_json::Value>,
+}
+
+fn default_discovery_tag() -> String {
+    "soft-watchdog".to_string()
+}
+
+fn default_discovery_interval() -> u64 {
+    60
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxmoxAuth {
     pub url: String,
-    pub user: String,
-    pub password: String,
+
+    /// User to log in as with a password, like `root@pam`.
+    /// Not needed when using `api_token`.
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// If set, this API token is used instead of the user/password ticket.
+    #[serde(default)]
+    pub api_token: Option<ApiToken>,
 
     #[serde(default)]
     pub allow_invalid_cert: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// The full token ID, like `watchdog@pve!monitor`.
+    pub id: String,
+    /// The token's secret UUID.
+    pub secret: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VmConfig {
-    pub node: String,
+    /// The node the guest lives on.
+    /// If missing, it is looked up in the cluster,
+    /// and it is looked up again whenever the guest migrates away.
+    #[serde(default)]
+    pub node: Option<String>,
     pub vmid: String,
     pub friendly_name: String,
 
+    /// Whether this is a QEMU VM or an LXC container.
+    /// Containers are reached with `pct` instead of the guest agent,
+    /// so the watchdog must run on the node that hosts them.
+    #[serde(default)]
+    pub kind: GuestKind,
+
     /// The maximum time from now that the VM can request,
     /// before we send a warning.
     /// In seconds.
@@ -36,11 +341,339 @@ pub struct VmConfig {
 
     pub reset_duration: u64,
 
+    /// Picks where the files exchanged with the guest go,
+    /// unless their paths are set below.
+    #[serde(default)]
+    pub guest_os: GuestOs,
+
+    /// Where the watchdog writes the current Unix time.
+    #[serde(default)]
+    pub current_time_path: Option<String>,
+
+    /// Where the guest writes its heartbeat, if `heartbeats` is empty.
+    #[serde(default)]
+    pub reset_after_path: Option<String>,
+
+    /// Where the watchdog writes the nonce for signed heartbeats.
+    #[serde(default)]
+    pub nonce_path: Option<String>,
+
+    /// If set, heartbeats must be signed with this secret,
+    /// over a nonce the watchdog writes into the guest,
+    /// so that no other process in the guest can forge them.
+    /// Whitespace around it is ignored, like in the guest's secret file.
+    #[serde(default)]
+    pub heartbeat_secret: Option<String>,
+
+    /// Heartbeat files the guest keeps up to date,
+    /// for guests that run several services we care about.
+    /// If empty, there is one called `main` at `reset_after_path`,
+    /// with the timings above.
+    #[serde(default)]
+    pub heartbeats: Vec<HeartbeatConfig>,
+
+    /// What to do when the grace period runs out, in order.
+    /// Each step gets its `timeout` to bring the guest down
+    /// before the next one is tried.
+    /// Defaults to a single hard reset.
+    #[serde(default = "default_escalation")]
+    pub escalation: Vec<EscalationStep>,
+
+    /// How long to wait for a reset task in Proxmox to finish
+    /// before considering it failed.
+    /// In seconds.
+    #[serde(default = "default_task_timeout")]
+    pub task_timeout: u64,
+
+    /// If set, diagnostics are collected from the guest into a new directory here
+    /// right before it is reset.
+    /// The console screenshot among them needs `proxmox_auth` to log in as root@pam
+    /// with a password, since Proxmox only lets root@pam run monitor commands other than `info`;
+    /// it also can't be taken into a path with spaces or quotes.
+    #[serde(default)]
+    pub incident_dir: Option<std::path::PathBuf>,
+
+    /// How long each diagnostics step may take, so that they don't delay the reset much.
+    /// In seconds.
+    #[serde(default = "default_diagnostics_timeout")]
+    pub diagnostics_timeout: u64,
+
+    /// Shorthand for a Telegram notifier used only by this guest.
+    #[serde(default)]
     pub telegram_bot_token: Option<String>,
+    #[serde(default)]
     pub telegram_chat_id: Option<String>,
+    #[serde(default = "default_telegram_api_url")]
+    pub telegram_api_url: String,
+
+    /// Names of the notifiers from `Config.notifiers` to send messages to.
+    /// If missing, all of them are used.
+    #[serde(default)]
+    pub notifiers: Option<Vec<String>>,
 
     /// If this is true, then enforcing will not happen.
     /// Instead, we'll send a message if we would reset the VM.
     #[serde(default)]
     pub dry_run: bool,
+
+    /// Instead of a new message at every threshold of the grace period,
+    /// send one countdown message and keep editing it.
+    /// Only backends that can edit messages, like Telegram, show the countdown.
+    #[serde(default)]
+    pub live_countdown: bool,
+
+    /// Which events go to which notifiers.
+    /// If empty, every event goes to every notifier.
+    /// Otherwise, a notifier only gets the events of the routes that name it.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+
+    /// Limits how many messages this guest can send,
+    /// so that a guest flapping between OK and the grace period can't flood the chat.
+    /// Critical messages are never held back,
+    /// and neither are messages to `pagerduty` and `alertmanager`.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// A token bucket: up to `burst` messages at once,
+/// and `per_hour` more over every hour after that.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_hour: u32,
+}
+
+/// One heartbeat file in the guest, usually kept up to date by one service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeartbeatConfig {
+    /// Used in messages, like `database`.
+    pub name: String,
+    pub path: String,
+
+    /// Like the guest's `max_no_warning_interval`, which is used if missing.
+    #[serde(default)]
+    pub max_no_warning_interval: Option<u64>,
+
+    /// Like the guest's `grace_period`, which is used if missing.
+    #[serde(default)]
+    pub grace_period: Option<u64>,
+
+    /// If false, the heartbeat lapsing is only reported,
+    /// and doesn't start the grace period.
+    #[serde(default = "default_heartbeat_required")]
+    pub required: bool,
+}
+
+fn default_heartbeat_required() -> bool {
+    true
+}
+
+/// Sends some of a guest's events to some of its notifiers,
+/// like resets to the on-call channel and everything else to a log channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+    pub notifiers: Vec<String>,
+
+    /// Events less severe than this are not sent.
+    #[serde(default)]
+    pub min_severity: Severity,
+
+    /// If not empty, only these kinds of events are sent.
+    #[serde(default)]
+    pub kinds: Vec<EventKind>,
+}
+
+impl Route {
+    pub fn matches(&self, kind: EventKind, severity: Severity) -> bool {
+        severity >= self.min_severity && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// What a message from the watchdog is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Monitoring started, stopped or resumed.
+    Monitoring,
+    /// The guest moved to another node.
+    Migrated,
+    PoweredOn,
+    PoweredOff,
+    /// The guest is sending heartbeats again.
+    Recovered,
+    /// The guest asked for a reset time too far away.
+    Maintenance,
+    /// The guest stopped sending heartbeats, and the grace period started.
+    GracePeriod,
+    /// An optional heartbeat lapsed, which doesn't start the grace period.
+    HeartbeatLapsed,
+    /// Time left in the grace period.
+    Countdown,
+    /// The watchdog is resetting the guest.
+    Reset,
+    /// Resetting the guest did not work.
+    ResetFailed,
+    /// Diagnostics collected before a reset.
+    Diagnostics,
+    /// Someone sent the watchdog a command.
+    Command,
+    /// The watchdog itself can't do its job, like when the API rejects it.
+    WatchdogError,
+}
+
+impl EventKind {
+    pub fn severity(self) -> Severity {
+        match self {
+            EventKind::Monitoring
+            | EventKind::Migrated
+            | EventKind::PoweredOn
+            | EventKind::Recovered
+            | EventKind::Maintenance
+            | EventKind::Diagnostics
+            | EventKind::Command => Severity::Info,
+            EventKind::PoweredOff
+            | EventKind::GracePeriod
+            | EventKind::HeartbeatLapsed
+            | EventKind::Countdown => Severity::Warning,
+            EventKind::Reset | EventKind::ResetFailed | EventKind::WatchdogError => {
+                Severity::Critical
+            }
+        }
+    }
+}
+
+impl VmConfig {
+    pub fn current_time_path(&self) -> String {
+        self.guest_path(&self.current_time_path, "watchdog_current_unix_time")
+    }
+
+    pub fn reset_after_path(&self) -> String {
+        self.guest_path(&self.reset_after_path, "watchdog_reset_after")
+    }
+
+    pub fn nonce_path(&self) -> String {
+        self.guest_path(&self.nonce_path, "watchdog_nonce")
+    }
+
+    fn guest_path(&self, path: &Option<String>, file: &str) -> String {
+        match path {
+            Some(path) => path.clone(),
+            None => format!("{}{}", self.guest_os.dir(), file),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestOs {
+    #[default]
+    Linux,
+    /// The directory has to exist, since the guest agent doesn't create it.
+    Windows,
+}
+
+impl GuestOs {
+    /// Where the guest files go by default, with the trailing separator.
+    fn dir(self) -> &'static str {
+        match self {
+            GuestOs::Linux => "/tmp/",
+            GuestOs::Windows => r"C:\ProgramData\soft-watchdog\",
+        }
+    }
+
+    /// A command that prints the guest's uptime in seconds as its first word.
+    pub fn uptime_command(self) -> &'static [&'static str] {
+        match self {
+            GuestOs::Linux => &["cat", "/proc/uptime"],
+            GuestOs::Windows => &[
+                "powershell.exe",
+                "-NoProfile",
+                "-Command",
+                "[int64]((Get-Date) - (Get-CimInstance Win32_OperatingSystem).LastBootUpTime).TotalSeconds",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestKind {
+    #[default]
+    Qemu,
+    Lxc,
+}
+
+impl GuestKind {
+    /// The path segment Proxmox uses for this kind of guest.
+    pub fn api_segment(self) -> &'static str {
+        match self {
+            GuestKind::Qemu => "qemu",
+            GuestKind::Lxc => "lxc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscalationStep {
+    pub action: EscalationAction,
+
+    /// How long to wait for the guest to go down before trying the next step.
+    /// In seconds, 2 minutes if missing.
+    /// Ignored for `reset` and `stop_start`, which always take effect.
+    #[serde(default = "default_escalation_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EscalationAction {
+    /// Ask the guest agent to shut the guest down.
+    AgentShutdown,
+    /// Send an ACPI power button press (`status/shutdown`).
+    AcpiShutdown,
+    /// Hard reset (`status/reset`).
+    Reset,
+    /// Hard stop, then start again.
+    StopStart,
+}
+
+impl EscalationAction {
+    pub fn describe(self) -> &'static str {
+        match self {
+            EscalationAction::AgentShutdown => messages::AGENT_SHUTDOWN,
+            EscalationAction::AcpiShutdown => messages::ACPI_SHUTDOWN,
+            EscalationAction::Reset => messages::HARD_RESET,
+            EscalationAction::StopStart => messages::STOP_START,
+        }
+    }
+}
+
+fn default_task_timeout() -> u64 {
+    60
+}
+
+fn default_diagnostics_timeout() -> u64 {
+    5
+}
+
+/// Long enough for most guests to shut down cleanly.
+fn default_escalation_timeout() -> u64 {
+    120
+}
+
+fn default_escalation() -> Vec<EscalationStep> {
+    vec![EscalationStep {
+        action: EscalationAction::Reset,
+        timeout: 0,
+    }]
 }
@@ -0,0 +1,322 @@
+//! Finds guests to monitor by their Proxmox tag,
+//! and keeps the registry in sync as tags are added and removed.
+
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{api, config, registry};
+
+pub async fn run(
+    api: api::Api,
+    discovery: config::DiscoveryConfig,
+    registry: Arc<tokio::sync::Mutex<registry::Registry>>,
+) {
+    // Only the guests we started ourselves are ours to stop;
+    // the ones from `vm_configs` stay monitored regardless of tags.
+    let mut discovered: HashSet<String> = HashSet::new();
+
+    loop {
+        match scan(&api, &discovery).await {
+            Ok(guests) => {
+                let mut stopping = Vec::new();
+                {
+                    let mut registry = registry.lock().await;
+                    let mut seen = HashSet::new();
+
+                    for (vmid, vm_config) in guests {
+                        seen.insert(vmid.clone());
+
+                        // Without a usable config right now, keep monitoring it as before.
+                        let Some(vm_config) = vm_config else {
+                            continue;
+                        };
+
+                        if let Some(running) = registry.config(&vmid) {
+                            if discovered.contains(&vmid) && !same_config(running, &vm_config) {
+                                tracing::info!(
+                                    "VMID {} overrides changed, updating monitoring",
+                                    vmid
+                                );
+                                registry.reconfigure(vm_config);
+                            }
+                            continue;
+                        }
+
+                        tracing::info!("Discovered VMID {}", vmid);
+                        registry.start(vm_config);
+                        discovered.insert(vmid);
+                    }
+
+                    let gone: Vec<String> = discovered.difference(&seen).cloned().collect();
+                    for vmid in gone {
+                        tracing::info!("VMID {} is no longer tagged, stopping monitoring", vmid);
+                        stopping.extend(registry.stop(&vmid));
+                        discovered.remove(&vmid);
+                    }
+                }
+
+                // A monitor may take a while to stop, and the Telegram bot needs the registry.
+                for stop in stopping {
+                    stop.await;
+                }
+            }
+            Err(why) => {
+                // Keep whatever we are monitoring now, and try again later.
+                tracing::error!("Failed to discover tagged guests: {}", why);
+            }
+        }
+
+        tokio::time::sleep(std::time::Duration::from_secs(discovery.interval)).await;
+    }
+}
+
+/// Build the configs of all guests that currently have the discovery tag.
+/// A guest's config is `None` if it can't be built right now,
+/// like when its description can't be read.
+async fn scan(
+    api: &api::Api,
+    discovery: &config::DiscoveryConfig,
+) -> Result<Vec<(String, Option<config::VmConfig>)>, api::Error> {
+    let mut guests = Vec::new();
+
+    for guest in api.list_guests().await? {
+        if guest.template != 0 || !guest.tags().any(|tag| tag == discovery.tag) {
+            continue;
+        }
+
+        let mut vm_config = guest_config(discovery, &guest, "");
+        if let Ok(partial) = &vm_config {
+            // The description is only in the guest's own config,
+            // so it takes another request.
+            match api.get_guest_config(partial).await {
+                Ok(config) => {
+                    let description = config["description"].as_str().unwrap_or_default();
+                    vm_config = guest_config(discovery, &guest, description);
+                }
+                Err(why) => {
+                    // Going on without the description would drop its overrides.
+                    tracing::warn!("VMID {} failed to read description: {}", guest.vmid, why);
+                    guests.push((guest.vmid.to_string(), None));
+                    continue;
+                }
+            }
+        }
+
+        match vm_config {
+            Ok(mut vm_config) => {
+                // The node is looked up by the monitor itself,
+                // so that a migration does not count as a config change.
+                vm_config.node = None;
+                guests.push((vm_config.vmid.clone(), Some(vm_config)));
+            }
+            Err(why) => {
+                tracing::error!(
+                    "VMID {} has invalid watchdog overrides: {}",
+                    guest.vmid,
+                    why
+                );
+                guests.push((guest.vmid.to_string(), None));
+            }
+        }
+    }
+
+    Ok(guests)
+}
+
+/// The settings a guest's tags and description may change.
+/// Anyone who can edit a guest's options can write these,
+/// so they must not reach other guests or files on the host.
+const OVERRIDABLE: &[&str] = &[
+    "max_no_warning_interval",
+    "grace_period",
+    "reset_duration",
+    "task_timeout",
+    "diagnostics_timeout",
+    "guest_os",
+    "dry_run",
+    "live_countdown",
+];
+
+#[derive(Debug)]
+pub enum OverrideError {
+    /// The key is not in `OVERRIDABLE`.
+    NotOverridable(String),
+    Invalid(serde_json::Error),
+}
+
+impl std::fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverrideError::NotOverridable(key) => write!(f, "{key} cannot be overridden"),
+            OverrideError::Invalid(why) => write!(f, "{why}"),
+        }
+    }
+}
+
+impl std::error::Error for OverrideError {}
+
+impl From<serde_json::Error> for OverrideError {
+    fn from(why: serde_json::Error) -> Self {
+        OverrideError::Invalid(why)
+    }
+}
+
+/// Combine the discovery defaults with the overrides in the guest's tags and description.
+fn guest_config(
+    discovery: &config::DiscoveryConfig,
+    guest: &api::ClusterGuest,
+    description: &str,
+) -> Result<config::VmConfig, OverrideError> {
+    let mut fields = discovery.defaults.clone();
+    let prefix = format!("{}.", discovery.tag);
+
+    // Tags can't contain `=`, so they look like `soft-watchdog.grace_period.600`,
+    // or just `soft-watchdog.dry_run` for flags.
+    let tag_overrides = guest
+        .tags()
+        .filter_map(|tag| tag.strip_prefix(&prefix))
+        .map(|rest| match rest.split_once('.') {
+            Some((key, value)) => (override_key(key), override_value(value)),
+            None => (override_key(rest), true.into()),
+        });
+
+    // Description lines look like `soft-watchdog.grace_period = 600`.
+    let description_overrides = description
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(&prefix))
+        .filter_map(|rest| rest.split_once('='))
+        .map(|(key, value)| (override_key(key), override_value(value)));
+
+    for (key, value) in tag_overrides.chain(description_overrides) {
+        if !OVERRIDABLE.contains(&key.as_str()) {
+            return Err(OverrideError::NotOverridable(key));
+        }
+        fields.insert(key, value);
+    }
+
+    // Who the guest is comes from Proxmox, after the overrides,
+    // so that no guest can pass itself off as another one.
+    fields.insert("vmid".into(), guest.vmid.to_string().into());
+    fields.insert("node".into(), guest.node.clone().into());
+    fields.insert("kind".into(), serde_json::to_value(guest.kind)?);
+    fields.insert(
+        "friendly_name".into(),
+        guest.name.clone().unwrap_or(guest.vmid.to_string()).into(),
+    );
+
+    Ok(serde_json::from_value(serde_json::Value::Object(fields))?)
+}
+
+fn override_key(key: &str) -> String {
+    key.trim().replace('-', "_")
+}
+
+/// Values are JSON if they parse as such, and plain strings otherwise.
+fn override_value(value: &str) -> serde_json::Value {
+    let value = value.trim();
+    serde_json::from_str(value).unwrap_or_else(|_| value.into())
+}
+
+fn same_config(a: &config::VmConfig, b: &config::VmConfig) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovery() -> config::DiscoveryConfig {
+        serde_json::from_value(serde_json::json!({
+            "defaults": {
+                "max_no_warning_interval": 300,
+                "grace_period": 600,
+                "reset_duration": 300,
+                "telegram_chat_id": "-100123",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn guest(tags: &str) -> api::ClusterGuest {
+        serde_json::from_value(serde_json::json!({
+            "vmid": 100,
+            "node": "pve1",
+            "type": "qemu",
+            "name": "web",
+            "tags": tags,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn applies_tag_and_description_overrides() {
+        let vm_config = guest_config(
+            &discovery(),
+            &guest("prod;soft-watchdog;soft-watchdog.grace-period.900;soft-watchdog.dry_run"),
+            "Web server\nsoft-watchdog.guest_os = windows\n",
+        )
+        .unwrap();
+
+        assert_eq!(vm_config.grace_period, 900);
+        assert!(vm_config.dry_run);
+        assert_eq!(vm_config.guest_os, config::GuestOs::Windows);
+        assert_eq!(vm_config.max_no_warning_interval, 300);
+        assert_eq!(vm_config.telegram_chat_id.as_deref(), Some("-100123"));
+    }
+
+    #[test]
+    fn rejects_overrides_that_reach_beyond_the_guest() {
+        for description in [
+            "soft-watchdog.telegram_chat_id = -100999",
+            "soft-watchdog.incident_dir = /etc",
+            "soft-watchdog.heartbeat_secret = guessed",
+            "soft-watchdog.reset_after_path = /etc/shadow",
+            r#"soft-watchdog.escalation = [{"action": "hard_reset"}]"#,
+            r#"soft-watchdog.notifiers = ["pagerduty"]"#,
+            "soft-watchdog.vmid = 101",
+            "soft-watchdog.node = pve2",
+        ] {
+            let result = guest_config(&discovery(), &guest("soft-watchdog"), description);
+            assert!(
+                matches!(result, Err(OverrideError::NotOverridable(_))),
+                "{description:?}"
+            );
+        }
+
+        let result = guest_config(
+            &discovery(),
+            &guest("soft-watchdog;soft-watchdog.kind.lxc"),
+            "",
+        );
+        assert!(matches!(result, Err(OverrideError::NotOverridable(key)) if key == "kind"));
+    }
+
+    #[test]
+    fn identity_comes_from_proxmox() {
+        let mut discovery = discovery();
+        for (key, value) in [
+            ("vmid", "101"),
+            ("node", "pve2"),
+            ("kind", "lxc"),
+            ("friendly_name", "db"),
+        ] {
+            discovery.defaults.insert(key.into(), value.into());
+        }
+
+        let vm_config = guest_config(&discovery, &guest("soft-watchdog"), "").unwrap();
+
+        assert_eq!(vm_config.vmid, "100");
+        assert_eq!(vm_config.node.as_deref(), Some("pve1"));
+        assert_eq!(vm_config.kind, config::GuestKind::Qemu);
+        assert_eq!(vm_config.friendly_name, "web");
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let result = guest_config(
+            &discovery(),
+            &guest("soft-watchdog;soft-watchdog.grace_period.soon"),
+            "",
+        );
+        assert!(matches!(result, Err(OverrideError::Invalid(_))));
+    }
+}
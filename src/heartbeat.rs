@@ -0,0 +1,414 @@
+//! What guests write into their heartbeat file.
+//!
+//! The simplest heartbeat is a bare Unix time, after which the guest wants to be reset
+//! unless it writes a later one. Guests that want to say more write a JSON document:
+//!
+//! ```json
+//! {
+//!     "reset_after": 1700000000,
+//!     "status": "failing",
+//!     "reason": "example.service is down",
+//!     "checks": [{"name": "example.service", "status": "failing", "reason": "inactive"}]
+//! }
+//! ```
+//!
+//! If the watchdog has a secret for the guest, it also writes a fresh nonce into the guest,
+//! and heartbeats must carry that `nonce` and a `signature` made by [`sign`]:
+//! HMAC-SHA256 with the secret, over the nonce followed by `reset_after` in decimal
+//! (or nothing, if there is none), in hex.
+//! Whitespace around the secret is left out, so a secret file ending in a newline
+//! works with the same secret in the watchdog's config.
+//! That way stray processes can't forge them, and leftovers from before a hang don't count.
+
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Ok,
+    /// The guest is up, but something on it is broken,
+    /// so it should go into the grace period right away.
+    Failing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// Unix time after which the guest wants to be reset.
+    /// Only needed when the status is `ok`.
+    #[serde(default)]
+    pub reset_after: Option<u64>,
+
+    /// The verdict of the guest on itself.
+    /// The checks are only there to explain it.
+    #[serde(default)]
+    pub status: Status,
+
+    #[serde(default)]
+    pub reason: Option<String>,
+
+    #[serde(default)]
+    pub checks: Vec<Check>,
+
+    /// The nonce the watchdog wrote into the guest, for signed heartbeats.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// One thing the guest looked at, like a service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    #[serde(default)]
+    pub status: Status,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// The end of the year 9999.
+/// Later times are surely garbage, and don't fit in every clock or date type.
+pub const MAX_RESET_AFTER: u64 = 253_402_300_799;
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// Neither a number nor a JSON heartbeat.
+    Json(serde_json::Error),
+
+    /// The guest says it's OK, but not until when.
+    MissingResetAfter,
+
+    /// `reset_after` is past [`MAX_RESET_AFTER`].
+    OutOfRange(u64),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Json(why) => write!(f, "not a Unix time or a JSON heartbeat: {why}"),
+            ParseError::MissingResetAfter => write!(f, "status is ok, but reset_after is missing"),
+            ParseError::OutOfRange(reset_after) => {
+                write!(f, "reset_after {reset_after} is too far in the future")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// PowerShell writes byte order marks, UTF-16 and CRLF line endings,
+/// depending on its version and on the cmdlet.
+/// Heartbeats are plain ASCII, so all of that can go.
+fn clean(text: &str) -> String {
+    // A byte order mark, either decoded, or passed through byte by byte,
+    // as UTF-8 or UTF-16 in either order.
+    let text = [
+        "\u{feff}",
+        "\u{ef}\u{bb}\u{bf}",
+        "\u{ff}\u{fe}",
+        "\u{fe}\u{ff}",
+    ]
+    .iter()
+    .find_map(|bom| text.strip_prefix(bom))
+    .unwrap_or(text);
+    // UTF-16 turns ASCII into pairs with a zero byte.
+    text.chars().filter(|c| *c != '\0').collect()
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The heartbeat has no nonce or no signature.
+    Unsigned,
+
+    /// The nonce isn't one the watchdog handed out recently.
+    StaleNonce,
+
+    /// The signature doesn't match, so the guest doesn't know the secret.
+    Mismatch,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "the heartbeat is not signed"),
+            SignatureError::StaleNonce => write!(f, "the nonce is old or unknown"),
+            SignatureError::Mismatch => write!(f, "the signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// The `signature` for a heartbeat, as described above.
+pub fn sign(secret: &[u8], nonce: &str, reset_after: Option<u64>) -> String {
+    hex::encode(mac(secret, nonce, reset_after).finalize().into_bytes())
+}
+
+fn mac(secret: &[u8], nonce: &str, reset_after: Option<u64>) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.trim_ascii()).expect("HMAC takes keys of any length");
+    mac.update(nonce.as_bytes());
+    if let Some(reset_after) = reset_after {
+        mac.update(reset_after.to_string().as_bytes());
+    }
+    mac
+}
+
+impl Heartbeat {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let text = clean(text);
+        let text = text.trim();
+        let heartbeat = match text.parse::<u64>() {
+            Ok(reset_after) => Self {
+                reset_after: Some(reset_after),
+                status: Status::Ok,
+                reason: None,
+                checks: Vec::new(),
+                nonce: None,
+                signature: None,
+            },
+            Err(_) => serde_json::from_str(text).map_err(ParseError::Json)?,
+        };
+
+        match heartbeat.reset_after {
+            Some(reset_after) if reset_after > MAX_RESET_AFTER => {
+                Err(ParseError::OutOfRange(reset_after))
+            }
+            None if heartbeat.status == Status::Ok => Err(ParseError::MissingResetAfter),
+            _ => Ok(heartbeat),
+        }
+    }
+
+    /// `reset_after` as a point in time, if it is there and the clock can hold it.
+    pub fn reset_time(&self) -> Option<std::time::SystemTime> {
+        std::time::SystemTime::UNIX_EPOCH
+            .checked_add(std::time::Duration::from_secs(self.reset_after?))
+    }
+
+    /// Check that the heartbeat was signed with the secret,
+    /// over a nonce that `is_fresh` accepts.
+    pub fn verify(
+        &self,
+        secret: &[u8],
+        is_fresh: impl Fn(&str) -> bool,
+    ) -> Result<(), SignatureError> {
+        let (Some(nonce), Some(signature)) = (&self.nonce, &self.signature) else {
+            return Err(SignatureError::Unsigned);
+        };
+        if !is_fresh(nonce) {
+            return Err(SignatureError::StaleNonce);
+        }
+        let signature = hex::decode(signature.trim()).map_err(|_| SignatureError::Mismatch)?;
+        mac(secret, nonce, self.reset_after)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+
+    /// The explanation of the guest, or else the failing checks, if there is anything to say.
+    pub fn reason(&self) -> Option<String> {
+        if let Some(reason) = &self.reason {
+            return Some(reason.clone());
+        }
+        let failing: Vec<String> = self
+            .checks
+            .iter()
+            .filter(|check| check.status == Status::Failing)
+            .map(|check| match &check.reason {
+                Some(reason) => format!("{}: {}", check.name, reason),
+                None => check.name.clone(),
+            })
+            .collect();
+        if failing.is_empty() {
+            None
+        } else {
+            Some(failing.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"s3cret";
+
+    /// What PVE hands back for files that aren't UTF-8: one char per byte.
+    fn as_latin1(bytes: &[u8]) -> String {
+        bytes.iter().map(|&byte| byte as char).collect()
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn signed(nonce: &str, reset_after: Option<u64>, secret: &[u8]) -> Heartbeat {
+        Heartbeat {
+            reset_after,
+            status: if reset_after.is_some() {
+                Status::Ok
+            } else {
+                Status::Failing
+            },
+            reason: None,
+            checks: Vec::new(),
+            nonce: Some(nonce.to_string()),
+            signature: Some(sign(secret, nonce, reset_after)),
+        }
+    }
+
+    #[test]
+    fn parses_a_plain_unix_time() {
+        let heartbeat = Heartbeat::parse("1700000000\n").unwrap();
+        assert_eq!(heartbeat.reset_after, Some(1700000000));
+        assert_eq!(heartbeat.status, Status::Ok);
+    }
+
+    #[test]
+    fn parses_crlf() {
+        let heartbeat = Heartbeat::parse("1700000000\r\n").unwrap();
+        assert_eq!(heartbeat.reset_after, Some(1700000000));
+    }
+
+    #[test]
+    fn parses_a_utf8_bom() {
+        for text in ["\u{feff}1700000000\r\n", "\u{ef}\u{bb}\u{bf}1700000000\r\n"] {
+            let heartbeat = Heartbeat::parse(text).unwrap();
+            assert_eq!(heartbeat.reset_after, Some(1700000000), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn parses_utf16_with_a_bom() {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(utf16le("1700000000\r\n"));
+        let heartbeat = Heartbeat::parse(&as_latin1(&bytes)).unwrap();
+        assert_eq!(heartbeat.reset_after, Some(1700000000));
+
+        let mut bytes = vec![0xfe, 0xff];
+        bytes.extend("1700000000".encode_utf16().flat_map(u16::to_be_bytes));
+        let heartbeat = Heartbeat::parse(&as_latin1(&bytes)).unwrap();
+        assert_eq!(heartbeat.reset_after, Some(1700000000));
+    }
+
+    #[test]
+    fn parses_nul_padded_utf16() {
+        let text = as_latin1(&utf16le(r#"{"reset_after": 1700000000, "status": "ok"}"#));
+        let heartbeat = Heartbeat::parse(&text).unwrap();
+        assert_eq!(heartbeat.reset_after, Some(1700000000));
+    }
+
+    #[test]
+    fn parses_json() {
+        let heartbeat = Heartbeat::parse(
+            r#"{"status": "failing", "checks": [{"name": "db", "status": "failing", "reason": "inactive"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(heartbeat.status, Status::Failing);
+        assert_eq!(heartbeat.reset_after, None);
+        assert_eq!(heartbeat.reason().as_deref(), Some("db: inactive"));
+    }
+
+    #[test]
+    fn rejects_ok_json_without_reset_after() {
+        assert!(matches!(
+            Heartbeat::parse(r#"{"status": "ok"}"#),
+            Err(ParseError::MissingResetAfter)
+        ));
+    }
+
+    #[test]
+    fn rejects_reset_after_out_of_range() {
+        assert!(matches!(
+            Heartbeat::parse(&u64::MAX.to_string()),
+            Err(ParseError::OutOfRange(u64::MAX))
+        ));
+        assert!(matches!(
+            Heartbeat::parse(&format!(r#"{{"reset_after": {}}}"#, MAX_RESET_AFTER + 1)),
+            Err(ParseError::OutOfRange(_))
+        ));
+        assert!(Heartbeat::parse(&MAX_RESET_AFTER.to_string()).is_ok());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(Heartbeat::parse("soon"), Err(ParseError::Json(_))));
+    }
+
+    #[test]
+    fn signed_heartbeats_verify() {
+        for reset_after in [Some(1700000000), None] {
+            let heartbeat = signed("abcd", reset_after, SECRET);
+            let text = serde_json::to_string(&heartbeat).unwrap();
+            let parsed = Heartbeat::parse(&text).unwrap();
+            assert!(parsed.verify(SECRET, |nonce| nonce == "abcd").is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_unsigned_heartbeats() {
+        let heartbeat = Heartbeat::parse("1700000000").unwrap();
+        assert!(matches!(
+            heartbeat.verify(SECRET, |_| true),
+            Err(SignatureError::Unsigned)
+        ));
+    }
+
+    #[test]
+    fn rejects_stale_nonces() {
+        let heartbeat = signed("abcd", Some(1700000000), SECRET);
+        assert!(matches!(
+            heartbeat.verify(SECRET, |nonce| nonce == "efgh"),
+            Err(SignatureError::StaleNonce)
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_signatures() {
+        let heartbeat = signed("abcd", Some(1700000000), b"other secret");
+        assert!(matches!(
+            heartbeat.verify(SECRET, |_| true),
+            Err(SignatureError::Mismatch)
+        ));
+
+        // A later reset time can't be swapped in without the secret.
+        let mut heartbeat = signed("abcd", Some(1700000000), SECRET);
+        heartbeat.reset_after = Some(1800000000);
+        assert!(matches!(
+            heartbeat.verify(SECRET, |_| true),
+            Err(SignatureError::Mismatch)
+        ));
+
+        let mut heartbeat = signed("abcd", Some(1700000000), SECRET);
+        heartbeat.signature = Some("not hex".to_string());
+        assert!(matches!(
+            heartbeat.verify(SECRET, |_| true),
+            Err(SignatureError::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn signature_is_hmac_sha256_over_nonce_and_reset_after() {
+        // `printf abcd1700000000 | openssl dgst -sha256 -hmac s3cret`
+        assert_eq!(
+            sign(SECRET, "abcd", Some(1700000000)),
+            "23300b73daca06cc2d8c231bf7950210e2d5836345f5f6ca2c122d143a46b18f"
+        );
+    }
+
+    #[test]
+    fn whitespace_around_the_secret_does_not_matter() {
+        // Like a secret file with a newline, and a config value with a stray space.
+        let heartbeat = signed("abcd", Some(1700000000), b"s3cret\n");
+        assert!(heartbeat.verify(b" s3cret ", |_| true).is_ok());
+        assert!(heartbeat.verify(SECRET, |_| true).is_ok());
+        assert!(matches!(
+            heartbeat.verify(b"s3 cret", |_| true),
+            Err(SignatureError::Mismatch)
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+//! Evidence collected from a hung guest right before it is reset,
+//! so that we can find out afterwards why it hung.
+
+use serde::Serialize;
+
+use crate::{api, config};
+
+/// Lines of the guest's journal to keep.
+const JOURNAL_LINES: &str = "200";
+
+/// The record of one incident, saved as `incident.json` in its directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct Incident {
+    pub vmid: String,
+    pub friendly_name: String,
+    pub created_at: String,
+
+    #[serde(skip)]
+    pub dir: std::path::PathBuf,
+
+    /// Files collected into the directory.
+    pub files: Vec<String>,
+
+    /// Steps that failed, and why.
+    pub errors: Vec<String>,
+
+    /// The guest's console, if we could grab it.
+    pub screenshot: Option<std::path::PathBuf>,
+}
+
+impl Incident {
+    /// Collect whatever we can from the guest into a new directory under `root`.
+    /// Every step is limited to `diagnostics_timeout`, and they all run at once.
+    #[tracing::instrument(skip(api, config, root))]
+    pub async fn collect(
+        api: &api::Api,
+        config: &config::VmConfig,
+        root: &std::path::Path,
+    ) -> std::io::Result<Self> {
+        let now = chrono::Utc::now();
+        let dir = root.join(format!("{}-{}", config.vmid, now.format("%Y%m%d-%H%M%S")));
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let timeout = std::time::Duration::from_secs(config.diagnostics_timeout);
+        let is_qemu = config.kind == config::GuestKind::Qemu;
+
+        let (status, vm_config, osinfo, fsinfo, journal) = tokio::join!(
+            step(timeout, async {
+                json(api.get_guest_json(config, "status/current").await)
+            }),
+            step(timeout, async { json(api.get_guest_config(config).await) }),
+            step(timeout, async {
+                if !is_qemu {
+                    return Err(api::Error::CommandFailed(
+                        "containers have no guest agent".into(),
+                    ));
+                }
+                json(api.get_guest_json(config, "agent/get-osinfo").await)
+            }),
+            step(timeout, async {
+                if !is_qemu {
+                    return Err(api::Error::CommandFailed(
+                        "containers have no guest agent".into(),
+                    ));
+                }
+                json(api.get_guest_json(config, "agent/get-fsinfo").await)
+            }),
+            step(timeout, async {
+                api.guest_exec(
+                    config,
+                    &["journalctl", "--no-pager", "-n", JOURNAL_LINES],
+                    timeout,
+                )
+                .await
+            }),
+        );
+
+        let mut incident = Incident {
+            vmid: config.vmid.clone(),
+            friendly_name: config.friendly_name.clone(),
+            created_at: now.to_rfc3339(),
+            dir,
+            files: Vec::new(),
+            errors: Vec::new(),
+            screenshot: None,
+        };
+
+        for (file, result) in [
+            ("status.json", status),
+            ("config.json", vm_config),
+            ("osinfo.json", osinfo),
+            ("fsinfo.json", fsinfo),
+            ("journal.txt", journal),
+        ] {
+            match result {
+                Ok(content) => {
+                    tokio::fs::write(incident.dir.join(file), content).await?;
+                    incident.files.push(file.to_string());
+                }
+                Err(why) => incident.errors.push(format!("{file}: {why}")),
+            }
+        }
+
+        incident.save().await?;
+        Ok(incident)
+    }
+
+    /// Write the record into `incident.json`.
+    pub async fn save(&self) -> std::io::Result<()> {
+        let record = serde_json::to_vec_pretty(self).expect("incident is serializable");
+        tokio::fs::write(self.dir.join("incident.json"), record).await
+    }
+}
+
+async fn step(
+    timeout: std::time::Duration,
+    future: impl std::future::Future<Output = Result<String, api::Error>>,
+) -> Result<String, String> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(Ok(content)) => Ok(content),
+        Ok(Err(why)) => Err(why.to_string()),
+        Err(_) => Err(format!("timed out after {} seconds", timeout.as_secs())),
+    }
+}
+
+fn json(value: Result<serde_json::Value, api::Error>) -> Result<String, api::Error> {
+    Ok(serde_json::to_string_pretty(&value?).expect("JSON value is serializable"))
+}
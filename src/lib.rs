@@ -0,0 +1,6 @@
+//! The parts shared between the watchdog on the host
+//! and `soft-watchdog-feed` in the guest.
+//! Only the guest protocol is here on purpose: the rest of the watchdog lives in the binary,
+//! so that it is free to change without keeping a library API stable.
+
+pub mod heartbeat;
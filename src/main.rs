@@ -1,8 +1,18 @@
-use tracing::Instrument;
+use std::sync::Arc;
+
+use proxmox_soft_watchdog::heartbeat;
 
 mod api;
 mod config;
-pub mod monitoring;
+mod discovery;
+mod incident;
+mod messages;
+mod monitoring;
+mod notify;
+mod pct;
+mod registry;
+mod telegram_bot;
+mod templates;
 
 #[tokio::main]
 async fn main() {
@@ -15,28 +25,38 @@ async fn main() {
     let config_text = std::fs::read_to_string(file_name).expect("cannot read config file");
     let config: config::Config = serde_json::from_str(&config_text).expect("cannot parse config");
 
-    println!("{:#?}", config);
+    // The config holds passwords and tokens, so only say how much of it there is.
+    tracing::info!(
+        "Loaded config with {} VMs and {} notifiers",
+        config.vm_configs.len(),
+        config.notifiers.len()
+    );
+
+    let api = api::Api::from_config(&config.proxmox_auth).expect("cannot set up Proxmox API");
 
-    let api = api::Api::from_config(&config.proxmox_auth);
+    api.login().await.expect("cannot log in to Proxmox API");
 
-    api.get_ticket().await;
+    let notifiers = notify::Notifiers::from_config(&config).expect("cannot set up notifiers");
+    if let Some(sender) = notifiers.outbox_sender() {
+        tokio::spawn(sender);
+    }
+
+    let registry = Arc::new(tokio::sync::Mutex::new(registry::Registry::new(
+        api.clone(),
+        notifiers,
+    )));
 
     for vm_config in config.vm_configs {
-        tokio::spawn(test_single_vm(api.clone(), vm_config));
+        registry.lock().await.start(vm_config);
     }
 
-    tokio::signal::ctrl_c().await.unwrap();
-}
+    if let Some(discovery) = config.discovery {
+        tokio::spawn(discovery::run(api.clone(), discovery, registry.clone()));
+    }
 
-async fn test_single_vm(api: api::Api, vm_config: config::VmConfig) {
-    let mut monitor = monitoring::SingleMachineMonitoring::new(api.clone(), vm_config.clone());
-    monitor.say("Monitoring loop started!").await;
-    loop {
-        let vmid = &vm_config.vmid;
-        monitor
-            .tick()
-            .instrument(tracing::info_span!("tick", vmid = vmid))
-            .await;
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    if let Some(telegram_bot) = config.telegram_bot {
+        tokio::spawn(telegram_bot::run(telegram_bot, registry.clone()));
     }
+
+    tokio::signal::ctrl_c().await.unwrap();
 }
@@ -0,0 +1,87 @@
+//! The English text of every message the watchdog sends.
+//!
+//! They are Handlebars templates, with `{{name}}` for each variable,
+//! and double as the keys of the bundled translations in `templates`.
+
+macro_rules! messages {
+    ($($name:ident = $text:literal;)*) => {
+        $(pub const $name: &str = $text;)*
+
+        /// Every message above, to check that each one has its translations.
+        #[cfg(test)]
+        pub const ALL: &[&str] = &[$($name),*];
+    };
+}
+
+messages! {
+    MONITORING_STARTED = "Monitoring loop started!";
+    MONITORING_STOPPED = "Monitoring loop stopped";
+    MONITORING_CRASHED = "Monitoring loop crashed and was started over";
+    MIGRATED = "Machine has moved from node {{from}} to node {{to}}";
+    CONTAINER_ON_OTHER_NODE = "Container is on node {{node}}, but the watchdog runs on node {{local_node}} and can only reach containers there, so it is not monitored until it comes back";
+    POWERED_ON = "Machine has been powered on, beginnning reset timer";
+    SHUT_DOWN_AFTER_STEP = "Machine has shut down after escalation step {{step}}, starting it again";
+    START_FAILED = "Failed to start machine: {{error}}. Retrying at {{retry_time}}";
+    STEP_DID_NOT_SHUT_DOWN = "Escalation step {{step}} did not shut the machine down in time";
+    POWERED_OFF = "Machine has been powered off, stopping monitoring";
+    SNOOZE_OVER = "Snooze is over, resuming monitoring";
+    RESET_TIMER_COMPLETED = "Machine reset timer has completed, resuming monitoring";
+    RETRYING_ESCALATION = "Retrying escalation after the failed reset";
+    PINGS_FAILED = "The machine has failed to respond to 5 {{channel}} pings in a row. Grace period started";
+    CURRENT_TIME_WRITE_FAILED = "Watchdog failed to write the current time to the guest into {{path}}. Grace period started";
+    NONCE_WRITE_FAILED = "Watchdog failed to write the nonce to the guest into {{path}}. Grace period started";
+    HEARTBEAT_REJECTED = "Heartbeat {{heartbeat}} at {{path}} was rejected: {{error}}. {{t consequence}}";
+    HEARTBEAT_READ_FAILED = "Watchdog failed to read heartbeat {{heartbeat}} from the guest at {{path}}. Perhaps the file doesn't exist? {{t consequence}}";
+    HEARTBEAT_PARSE_FAILED = "Watchdog failed to parse heartbeat {{heartbeat}} at {{path}} as a Unix time or a JSON heartbeat. {{t consequence}}";
+    HEARTBEAT_REPORTS_PROBLEM = "Machine reports a problem in heartbeat {{heartbeat}}: {{reason}}. {{t consequence}}";
+    GRACE_PERIOD_STARTED = "Grace period started";
+    OPTIONAL_HEARTBEAT_LAPSED = "This heartbeat is optional, so the grace period is not started";
+    CURRENT_HEARTBEAT_TEXT = "The current text in {{path}} is: \n\n{{content}}";
+    TOO_FAR = "Machine requested reset at {{reset_time}} in heartbeat {{heartbeat}}, which is too far into the future. This is OK if you are performing manual maintenance.";
+    MACHINE_OK = "Machine is OK";
+    HEARTBEAT_NOT_UPDATED = "Machine has not updated heartbeat {{heartbeat}} at {{path}} in a while (last update was at {{reset_time}}). {{t consequence}}";
+    HEARTBEAT_UNREADABLE = "Could not read heartbeat {{heartbeat}} from the file at {{path}}. {{t consequence}}";
+    GRACE_PERIOD_EXPIRED = "Grace period has expired. Resetting machine now";
+    DRY_RUN_NOT_RESETTING = "Dry-run mode: not actually resetting the machine";
+    WILL_RESET_IN = "Machine will reset in {{duration seconds}} unless the issue is fixed";
+    WILL_RESET_IN_UNDER_A_MINUTE = "Machine will reset in less than a minute unless the issue is fixed";
+    COUNTDOWN_RECOVERED = "Grace period is over: machine recovered";
+    COUNTDOWN_RESET_SKIPPED = "Grace period is over: reset skipped in dry-run mode";
+    COUNTDOWN_RESET_PERFORMED = "Grace period is over: reset performed";
+    COUNTDOWN_ESCALATING = "Grace period is over: escalation in progress";
+    COUNTDOWN_RESET_FAILED = "Grace period is over: reset failed";
+    COUNTDOWN_POWERED_OFF = "Grace period is over: machine was powered off";
+    COUNTDOWN_ACKNOWLEDGED = "Countdown stopped: problem acknowledged";
+    COUNTDOWN_SNOOZED = "Countdown stopped: monitoring snoozed";
+    COUNTDOWN_STOPPED = "Countdown stopped";
+    SNOOZED_UNTIL = "Monitoring snoozed until {{until}}";
+    ACKNOWLEDGED = "Problem acknowledged, the machine will not be reset until it reports OK again";
+    NOTHING_TO_ACKNOWLEDGE = "Nothing to acknowledge, the machine is not in its grace period";
+    RESET_REQUESTED = "Reset requested by command";
+    RESET_REQUESTED_ALREADY_RESETTING = "Reset requested by command, but the machine is already being reset";
+    RESET_REQUESTED_POWERED_OFF = "Reset requested by command, but the machine is powered off";
+    DRY_RUN_ON = "Dry-run mode turned on";
+    DRY_RUN_OFF = "Dry-run mode turned off";
+    ESCALATION_STEP = "Escalation step {{step}}/{{steps}}: {{t action}}";
+    AGENT_SHUTDOWN = "shutdown through the guest agent";
+    ACPI_SHUTDOWN = "ACPI shutdown";
+    HARD_RESET = "hard reset";
+    STOP_START = "hard stop and start";
+    STEP_FAILED = "Escalation step {{step}} failed: {{error}}";
+    RESET_TASK_DONE = "Reset task {{outcome}}";
+    STEP_TASK_FAILED = "Escalation step {{step}} failed: task {{outcome}}";
+    TASK_UNCONFIRMED = "Could not confirm that reset task {{upid}} succeeded: {{error}}";
+    ALL_STEPS_FAILED = "All escalation steps have failed, the machine was NOT reset. Retrying at {{retry_time}}";
+    DIAGNOSTICS_FAILED = "Failed to save diagnostics: {{error}}";
+    DIAGNOSTICS_COLLECTED = "Collected diagnostics into {{dir}}";
+    DIAGNOSTICS_PARTLY_COLLECTED = "Collected diagnostics into {{dir}}, but some steps failed:\n{{errors}}";
+    SCREENSHOT_CAPTION = "Console right before the reset";
+    NO_REBOOT_HOST_UPTIME = "Reset command accepted but guest did not reboot: its uptime is {{uptime}}s, and was {{uptime_before}}s before the reset, but the reset was {{since_reset}}s ago";
+    UPTIME_UNREADABLE = "Could not check that the reset rebooted the guest, since its uptime can't be read: {{error}}";
+    NO_REBOOT_GUEST_UPTIME = "Reset command accepted but guest did not reboot: its uptime is {{uptime}}s, but the reset was {{since_reset}}s ago";
+    DIGEST_HEADING = "{{count}} events in the last {{window}} seconds";
+    QUIET_HOURS_HEADING = "{{count}} events during quiet hours";
+    RATE_LIMITED_HEADING = "{{count}} earlier messages were not sent because of the rate limit";
+    DIGEST_SECTION = "Node {{node}}, {{kind}} ({{count}}):";
+    AUTH_FAILED = "Watchdog cannot authenticate to the Proxmox API, monitoring is paused: {{error}}";
+}
@@ -1,6 +1,4 @@
-use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
-
-use crate::{api, config};
+use crate::{api, config, heartbeat, incident, messages, notify, pct};
 
 pub enum SingleMachineMonitoringState {
     /// The machine's timer has been recently reset.
@@ -17,14 +15,206 @@ pub enum SingleMachineMonitoringState {
     /// Final reset will happen at the given Unixtime.
     GracePeriod(std::time::SystemTime),
 
+    /// The grace period has expired, and we are trying the escalation step at this index.
+    /// If the machine hasn't shut down by the given Unixtime, we try the next step.
+    Escalating(usize, std::time::SystemTime),
+
+    /// We have started a Proxmox task against the machine, like a reset,
+    /// and look at it once per tick until it ends or the given Unixtime passes.
+    FollowingTask {
+        upid: String,
+        purpose: TaskPurpose,
+        deadline: std::time::SystemTime,
+    },
+
     /// We have reset the machine, and are waiting for it to come back online.
     /// Resuming monitoring after the given Unixtime.
     Resetting(std::time::SystemTime),
 
+    /// Every escalation step has failed, so the machine was not reset.
+    /// We try again from the start at the given Unixtime,
+    /// unless the machine recovers by itself before then.
+    ResetFailed(std::time::SystemTime),
+
     /// The machine is powered off, so monitoring should not happen.
     PowerOff,
 }
 
+/// What a Proxmox task we follow is for, which decides what happens once it ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskPurpose {
+    /// Stopping the machine in this escalation step, to start it again afterwards.
+    Stop(usize),
+
+    /// Resetting or starting the machine in this escalation step.
+    Restart(usize),
+
+    /// Starting the machine after a graceful escalation step shut it down.
+    StartAfterShutdown,
+}
+
+impl SingleMachineMonitoringState {
+    /// A short human-readable description, for status replies.
+    pub fn describe(&self) -> String {
+        let at = |time: &std::time::SystemTime| {
+            let time: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(*time);
+            time.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+        };
+        match self {
+            SingleMachineMonitoringState::Ok(time) => format!("OK until {}", at(time)),
+            SingleMachineMonitoringState::NoData => "no data yet".to_string(),
+            SingleMachineMonitoringState::TooFar(time) => {
+                format!("requested reset far in the future, at {}", at(time))
+            }
+            SingleMachineMonitoringState::GracePeriod(time) => {
+                format!("in grace period, reset at {}", at(time))
+            }
+            SingleMachineMonitoringState::Escalating(step, time) => {
+                format!("escalation step {} until {}", step + 1, at(time))
+            }
+            SingleMachineMonitoringState::FollowingTask { upid, deadline, .. } => {
+                format!("waiting for task {} until {}", upid, at(deadline))
+            }
+            SingleMachineMonitoringState::Resetting(time) => {
+                format!("resetting, monitoring resumes at {}", at(time))
+            }
+            SingleMachineMonitoringState::ResetFailed(time) => {
+                format!("reset failed, retrying at {}", at(time))
+            }
+            SingleMachineMonitoringState::PowerOff => "powered off".to_string(),
+        }
+    }
+
+    /// When the current state runs out, if it does.
+    pub fn deadline(&self) -> Option<std::time::SystemTime> {
+        match self {
+            SingleMachineMonitoringState::Ok(time)
+            | SingleMachineMonitoringState::TooFar(time)
+            | SingleMachineMonitoringState::GracePeriod(time)
+            | SingleMachineMonitoringState::Escalating(_, time)
+            | SingleMachineMonitoringState::Resetting(time)
+            | SingleMachineMonitoringState::ResetFailed(time)
+            | SingleMachineMonitoringState::FollowingTask { deadline: time, .. } => Some(*time),
+            SingleMachineMonitoringState::NoData | SingleMachineMonitoringState::PowerOff => None,
+        }
+    }
+}
+
+/// Something a person asked the monitor to do, like through the Telegram bot.
+pub enum Command {
+    /// Reply with a description of the current state.
+    Status(tokio::sync::oneshot::Sender<String>),
+
+    /// Don't do anything for this long.
+    Snooze(std::time::Duration),
+
+    /// Someone is looking into it,
+    /// so don't reset the machine at the end of the current grace period,
+    /// but only once it reports OK again.
+    Ack,
+
+    /// Reset the machine now.
+    Reset,
+
+    /// Turn dry-run mode on or off.
+    DryRun(bool),
+
+    /// Use new settings, like changed overrides, keeping the current state.
+    Reconfigure(Box<config::VmConfig>),
+}
+
+/// Longer snoozes are cut down to this, so that a typo can't turn the watchdog off for good.
+const MAX_SNOOZE: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The most bytes of text from the guest that go into one message,
+/// so that a runaway file can't make the message too long to deliver.
+const MAX_GUEST_TEXT: usize = 1000;
+
+/// How much longer than the time since the reset
+/// the guest's uptime may be before we conclude it never rebooted.
+const UPTIME_SLACK: u64 = 30;
+
+/// What we knew about the guest when we started resetting it,
+/// so we can check afterwards that it really rebooted.
+struct ResetCheck {
+    at: std::time::SystemTime,
+    uptime_before: u64,
+
+    /// A QEMU hard reset keeps the QEMU process running,
+    /// so the hypervisor's uptime keeps counting,
+    /// and we have to ask the guest for its own uptime instead.
+    use_guest_uptime: bool,
+}
+
+/// What we know about one heartbeat of the guest.
+enum HeartbeatState {
+    /// Nothing read yet.
+    NoData,
+
+    /// The guest will write again before this Unixtime.
+    Ok(std::time::SystemTime),
+
+    /// The guest asked for a time unusually far into the future.
+    TooFar(std::time::SystemTime),
+
+    /// The heartbeat ran out, couldn't be read, or reports a problem.
+    Lapsed,
+}
+
+/// One heartbeat file in the guest, with the guest's own timings filled in.
+struct HeartbeatWatch {
+    name: String,
+    path: String,
+    max_no_warning_interval: u64,
+    grace_period: u64,
+    required: bool,
+    state: HeartbeatState,
+
+    /// What the guest last said was wrong, if it said anything.
+    reason: Option<String>,
+
+    /// The last heartbeat was signed with a nonce we don't know.
+    awaiting_nonce: bool,
+}
+
+impl HeartbeatWatch {
+    fn all(config: &config::VmConfig) -> Vec<Self> {
+        let main = [config::HeartbeatConfig {
+            name: "main".to_string(),
+            path: config.reset_after_path(),
+            max_no_warning_interval: None,
+            grace_period: None,
+            required: true,
+        }];
+        let heartbeats = if config.heartbeats.is_empty() {
+            &main[..]
+        } else {
+            &config.heartbeats
+        };
+
+        heartbeats
+            .iter()
+            .map(|heartbeat| Self {
+                name: heartbeat.name.clone(),
+                path: heartbeat.path.clone(),
+                max_no_warning_interval: heartbeat
+                    .max_no_warning_interval
+                    .unwrap_or(config.max_no_warning_interval),
+                grace_period: heartbeat.grace_period.unwrap_or(config.grace_period),
+                required: heartbeat.required,
+                state: HeartbeatState::NoData,
+                reason: None,
+                awaiting_nonce: false,
+            })
+            .collect()
+    }
+}
+
+/// How long the guest may keep signing with a nonce.
+/// The guest usually writes its heartbeats once a minute,
+/// while we hand out a new nonce every tick.
+const NONCE_LIFETIME: std::time::Duration = std::time::Duration::from_secs(300);
+
 const THRESHOLDS: &[(u64, &str)] = &[
     (60, "1 minute"),
     (120, "2 minutes"),
@@ -44,7 +234,7 @@ pub struct SingleMachineMonitoring {
 
     api: api::Api,
 
-    tg_client: reqwest_middleware::ClientWithMiddleware,
+    notifiers: notify::Notifiers,
 
     /// How many times in a row has the guest agent ping failed?
     ping_fail_count: u32,
@@ -52,27 +242,190 @@ pub struct SingleMachineMonitoring {
     /// The shortest threshold that we've sent a message about grace period for.
     /// None if we haven't sent a message yet.
     last_sent_threshold: Option<u64>,
+
+    /// Have we already told the chat that the Proxmox API is rejecting us?
+    auth_failure_reported: bool,
+
+    /// The node the guest was on before it disappeared from there,
+    /// so we can report where it moved to.
+    lost_node: Option<String>,
+
+    /// Have we already told the chat that the container is on another node?
+    remote_reported: bool,
+
+    /// The uptime from the latest `status/current`.
+    last_uptime: u64,
+
+    /// Set while we're resetting the machine, and checked once that's done.
+    reset_check: Option<ResetCheck>,
+
+    /// Starting the machine after a graceful shutdown failed,
+    /// so it is down because of us, and we keep trying to start it.
+    start_failed: bool,
+
+    /// If set, monitoring is paused until then.
+    snoozed_until: Option<std::time::SystemTime>,
+
+    /// Someone has acknowledged the current problem,
+    /// so the grace period does not end in a reset until the machine is OK again.
+    /// Only holds for the grace period it was given in.
+    acknowledged: bool,
+
+    /// Dry-run mode as last set by command, which wins over the settings,
+    /// so that new settings from discovery don't turn resets back on behind the operator's back.
+    dry_run_override: Option<bool>,
+
+    /// The live countdown message for the current grace period,
+    /// and the minutes left that it currently shows.
+    countdown: Option<(notify::LiveMessage, u64)>,
+
+    /// The heartbeat files the guest keeps up to date.
+    heartbeats: Vec<HeartbeatWatch>,
+
+    /// Where the time comes from, so that tests can move it forward.
+    clock: fn() -> std::time::SystemTime,
+
+    /// Nonces recently handed to the guest for signing heartbeats, and when.
+    nonces: Vec<(String, std::time::SystemTime)>,
+
+    /// When we handed out the first nonce.
+    nonces_since: Option<std::time::SystemTime>,
 }
 
 impl SingleMachineMonitoring {
-    pub fn new(api: api::Api, config: config::VmConfig) -> Self {
-        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+    pub fn new(api: api::Api, config: config::VmConfig, notifiers: notify::Notifiers) -> Self {
+        let heartbeats = HeartbeatWatch::all(&config);
         Self {
             state: SingleMachineMonitoringState::NoData,
             config,
             api,
             ping_fail_count: 0,
             last_sent_threshold: None,
-            tg_client: reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
-                .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-                .build(),
+            auth_failure_reported: false,
+            lost_node: None,
+            remote_reported: false,
+            last_uptime: 0,
+            reset_check: None,
+            start_failed: false,
+            snoozed_until: None,
+            acknowledged: false,
+            dry_run_override: None,
+            countdown: None,
+            heartbeats,
+            nonces: Vec::new(),
+            nonces_since: None,
+            notifiers,
+            clock: std::time::SystemTime::now,
+        }
+    }
+
+    fn now(&self) -> std::time::SystemTime {
+        (self.clock)()
+    }
+
+    fn dry_run(&self) -> bool {
+        self.dry_run_override.unwrap_or(self.config.dry_run)
+    }
+
+    /// Restarting the monitor would throw away a grace period or a reset in progress,
+    /// so new settings are applied in place, and heartbeats that stay keep what we know.
+    fn reconfigure(&mut self, mut config: config::VmConfig) {
+        // Where the guest is comes from the cluster, not from the settings.
+        config.node = self.config.node.take();
+        let mut heartbeats = HeartbeatWatch::all(&config);
+        for heartbeat in &mut heartbeats {
+            if let Some(old) = self
+                .heartbeats
+                .iter_mut()
+                .find(|old| old.name == heartbeat.name && old.path == heartbeat.path)
+            {
+                heartbeat.state = std::mem::replace(&mut old.state, HeartbeatState::NoData);
+                heartbeat.reason = old.reason.take();
+                heartbeat.awaiting_nonce = old.awaiting_nonce;
+            }
         }
+        self.heartbeats = heartbeats;
+        self.config = config;
+        tracing::info!("Settings changed, keeping the current state");
     }
 
     pub async fn tick(&mut self) {
-        let is_machine_running = self.api.get_is_machine_running(&self.config).await;
+        self.check().await;
+        self.end_acknowledgement();
+        self.update_countdown().await;
+    }
+
+    /// An acknowledgement is for the grace period it was given in,
+    /// so it must not hold back the next one.
+    fn end_acknowledgement(&mut self) {
+        if !matches!(self.state, SingleMachineMonitoringState::GracePeriod(_)) {
+            self.acknowledged = false;
+        }
+    }
+
+    async fn check(&mut self) {
+        // If we don't know where the guest is, find it first.
+        if self.config.node.is_none() {
+            match self.api.find_guest_node(&self.config).await {
+                Ok(node) => {
+                    match self.lost_node.take() {
+                        Some(lost_node) if lost_node == node => {}
+                        Some(lost_node) => {
+                            self.say(
+                                config::EventKind::Migrated,
+                                messages::MIGRATED,
+                                &[("from", lost_node.clone()), ("to", node.clone())],
+                            )
+                            .await;
+                            tracing::info!("Guest is on node {}", node);
+                        }
+                        None => tracing::info!("Guest is on node {}", node),
+                    }
+                    self.config.node = Some(node);
+                }
+                Err(why) => {
+                    if !self.is_host_side_failure("find_guest_node", &why).await {
+                        tracing::error!("Failed to find guest node: {}", why);
+                    }
+                    return;
+                }
+            }
+        }
+
+        // Containers are reached with `pct`, which only sees the ones on this node.
+        if self.config.kind == config::GuestKind::Lxc
+            && let (Some(node), Some(local_node)) = (&self.config.node, pct::local_node())
+            && *node != local_node
+        {
+            if !self.remote_reported {
+                self.remote_reported = true;
+                self.say(
+                    config::EventKind::WatchdogError,
+                    messages::CONTAINER_ON_OTHER_NODE,
+                    &[("local_node", local_node)],
+                )
+                .await;
+            }
+            // Look it up again on the next tick, to notice when it comes back.
+            self.lost_node = self.config.node.take();
+            return;
+        }
+        self.remote_reported = false;
+
+        // A task we started decides what happens next,
+        // whatever the machine looks like while it runs.
+        if let SingleMachineMonitoringState::FollowingTask { .. } = self.state {
+            self.check_task().await;
+            return;
+        }
+
+        let status = self.api.get_guest_status(&self.config).await;
+        if let Ok(status) = &status {
+            self.auth_failure_reported = false;
+            self.last_uptime = status.uptime;
+        }
 
-        match (is_machine_running, &self.state) {
+        match (status.map(|status| status.running), &self.state) {
             (Ok(false), SingleMachineMonitoringState::PowerOff) => {
                 tracing::debug!("Machine is still powered off, nothing to do.");
                 return;
@@ -80,30 +433,91 @@ impl SingleMachineMonitoring {
             (Ok(true), SingleMachineMonitoringState::PowerOff) => {
                 // Machine is now powered on, start monitoring.
                 tracing::debug!("Machine was off, is now on");
-                self.say("Machine has been powered on, beginnning reset timer")
+                self.say(config::EventKind::PoweredOn, messages::POWERED_ON, &[])
                     .await;
                 self.state = SingleMachineMonitoringState::Resetting(
-                    std::time::SystemTime::now()
-                        + std::time::Duration::from_secs(self.config.reset_duration),
+                    self.now() + std::time::Duration::from_secs(self.config.reset_duration),
                 );
                 self.ping_fail_count = 0;
             }
+            (Ok(false), SingleMachineMonitoringState::Escalating(step, _)) => {
+                // A graceful shutdown step worked,
+                // so now bring the machine back up.
+                let step = *step;
+                self.say(
+                    config::EventKind::Reset,
+                    messages::SHUT_DOWN_AFTER_STEP,
+                    &[("step", (step + 1).to_string())],
+                )
+                .await;
+                self.start_after_shutdown().await;
+                return;
+            }
+            (Ok(false), SingleMachineMonitoringState::ResetFailed(retry_time))
+                if self.start_failed =>
+            {
+                // We shut the machine down ourselves, so keep trying to bring it back.
+                if self.now() >= *retry_time {
+                    self.start_after_shutdown().await;
+                }
+                return;
+            }
+            (Ok(true), SingleMachineMonitoringState::Escalating(step, deadline)) => {
+                // The machine is still up; give the current step until its deadline,
+                // and don't look at heartbeats while it's shutting down.
+                let (step, deadline) = (*step, *deadline);
+                if self.now() >= deadline {
+                    self.say(
+                        config::EventKind::ResetFailed,
+                        messages::STEP_DID_NOT_SHUT_DOWN,
+                        &[("step", (step + 1).to_string())],
+                    )
+                    .await;
+                    self.escalate(step + 1).await;
+                }
+                return;
+            }
             (Ok(true), _) => {
                 // Machine is still powered on
             }
             (Ok(false), _) => {
                 tracing::debug!("Machine is now powered off, and we are still monitoring");
                 // Machine is now powered off, stop monitoring.
-                self.say("Machine has been powered off, stopping monitoring")
+                // A reset in progress can't be checked anymore.
+                self.reset_check = None;
+                self.say(config::EventKind::PoweredOff, messages::POWERED_OFF, &[])
                     .await;
                 self.state = SingleMachineMonitoringState::PowerOff;
                 return;
             }
             (Err(why), _) => {
-                // Error getting is_machine_running, just continue
-                tracing::error!("Failed to get is_machine_running: {:?}", why);
+                // We can't tell what the machine is doing,
+                // so don't change anything until the next tick.
+                if !self.is_host_side_failure("get_guest_status", &why).await {
+                    tracing::error!("Failed to get guest status: {}", why);
+                }
+                return;
+            }
+        }
+
+        // While snoozed, leave the machine alone entirely.
+        if let Some(snoozed_until) = self.snoozed_until {
+            if self.now() < snoozed_until {
+                tracing::debug!("Monitoring is snoozed");
                 return;
             }
+            self.snoozed_until = None;
+            self.say(config::EventKind::Monitoring, messages::SNOOZE_OVER, &[])
+                .await;
+            // What the guest said before the snooze is stale,
+            // but a reset that was going on carries on, and is still checked.
+            if !matches!(
+                self.state,
+                SingleMachineMonitoringState::Resetting(_)
+                    | SingleMachineMonitoringState::ResetFailed(_)
+            ) {
+                self.start_over();
+            }
         }
 
         // If we are not in GracePeriod,
@@ -113,24 +527,44 @@ impl SingleMachineMonitoring {
         }
 
         // If the machine is currently resetting, just wait until it's reset.
-        if let SingleMachineMonitoringState::Resetting(reset_time) = self.state {
-            if std::time::SystemTime::now() >= reset_time {
-                // The machine has reset,
-                // so resume monitoring.
-                self.say("Machine reset timer has completed, resuming monitoring")
-                    .await;
-                self.state = SingleMachineMonitoringState::NoData;
-            }
+        if let SingleMachineMonitoringState::Resetting(reset_time) = self.state
+            && self.now() >= reset_time
+        {
+            // The machine has reset,
+            // so resume monitoring.
+            self.say(
+                config::EventKind::Monitoring,
+                messages::RESET_TIMER_COMPLETED,
+                &[],
+            )
+            .await;
+            self.finish_reset().await;
+            self.start_over();
+        }
+
+        // If resetting failed earlier, and the machine still hasn't recovered,
+        // then go straight back to escalating.
+        if let SingleMachineMonitoringState::ResetFailed(retry_time) = self.state
+            && self.now() >= retry_time
+        {
+            self.say(
+                config::EventKind::ResetFailed,
+                messages::RETRYING_ESCALATION,
+                &[],
+            )
+            .await;
+            self.state = SingleMachineMonitoringState::GracePeriod(self.now());
         }
 
-        // If the machine was too far, but that state has now passed,
+        // If a heartbeat was too far, but that has now passed,
         // then it's back to normal.
-        if let SingleMachineMonitoringState::TooFar(reset_time) = self.state {
-            if std::time::SystemTime::now()
-                + std::time::Duration::from_secs(self.config.max_no_warning_interval)
-                >= reset_time
+        let now = self.now();
+        for heartbeat in &mut self.heartbeats {
+            if let HeartbeatState::TooFar(reset_time) = heartbeat.state
+                && now + std::time::Duration::from_secs(heartbeat.max_no_warning_interval)
+                    >= reset_time
             {
-                self.state = SingleMachineMonitoringState::Ok(reset_time);
+                heartbeat.state = HeartbeatState::Ok(reset_time);
             }
         }
 
@@ -139,6 +573,9 @@ impl SingleMachineMonitoring {
             Ok(()) => {
                 self.ping_fail_count = 0;
             }
+            Err(e) if self.is_host_side_failure("ping_guest_agent", &e).await => {
+                return;
+            }
             Err(e) => {
                 tracing::info!("VMID {} ping failed: {}", self.config.vmid, e);
                 self.ping_fail_count += 1;
@@ -146,194 +583,160 @@ impl SingleMachineMonitoring {
                 // If the machine failed 5 pings in a row,
                 // then move it to the grace period
                 // if it's not there already.
-                if let SingleMachineMonitoringState::Ok(_) = self.state {
-                    if self.ping_fail_count >= 5 {
-                        self.state = SingleMachineMonitoringState::GracePeriod(
-                            std::time::SystemTime::now()
-                                + std::time::Duration::from_secs(self.config.grace_period),
-                        );
-
-                        self.say("The machine has failed to respond to 5 QEMU guest-agent pings in a row. Grace period started").await;
-                    }
+                if let SingleMachineMonitoringState::Ok(_) = self.state
+                    && self.ping_fail_count >= 5
+                {
+                    self.state = SingleMachineMonitoringState::GracePeriod(
+                        self.now() + std::time::Duration::from_secs(self.config.grace_period),
+                    );
+
+                    let channel = match self.config.kind {
+                        config::GuestKind::Qemu => "QEMU guest-agent",
+                        config::GuestKind::Lxc => "pct exec",
+                    };
+                    self.say(
+                        config::EventKind::GracePeriod,
+                        messages::PINGS_FAILED,
+                        &[("channel", channel.to_string())],
+                    )
+                    .await;
                 }
             }
         }
 
+        // Only fresh required heartbeats can tell that the guest is OK;
+        // optional ones don't hold it back.
+        let mut read_all_required = false;
         if self.ping_fail_count == 0 {
             // Ping was successful,
             // now write the current time into the guest.
-            let current_time = std::time::SystemTime::now()
+            let current_time = self
+                .now()
                 .duration_since(std::time::SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs()
                 .to_string();
 
-            if let Err(why) = self
+            let current_time_path = self.config.current_time_path();
+            let written = match self
                 .api
-                .guest_agent_write_file(
-                    &self.config,
-                    "/tmp/watchdog_current_unix_time",
-                    &current_time.as_bytes(),
-                )
+                .guest_agent_write_file(&self.config, &current_time_path, current_time.as_bytes())
                 .await
             {
+                Ok(()) => self.write_nonce().await,
+                Err(why) => Err((messages::CURRENT_TIME_WRITE_FAILED, current_time_path, why)),
+            };
+
+            if let Err((template, path, why)) = written {
+                if self
+                    .is_host_side_failure("guest_agent_write_file", &why)
+                    .await
+                {
+                    return;
+                }
                 tracing::info!(
-                    "VMID {} write_file /tmp/watchdog_current_unix_time failed: {}",
+                    "VMID {} write_file {} failed: {}",
                     self.config.vmid,
+                    path,
                     why
                 );
 
+                if let api::Error::AgentNotRunning(_) = why {
+                    // The agent went away between the ping and the write,
+                    // so count it like a failed ping instead.
+                    self.ping_fail_count += 1;
+                }
                 // Failing writes are a big deal, so move it to the grace period right away.
-                if let SingleMachineMonitoringState::Ok(_) = self.state {
+                else if let SingleMachineMonitoringState::Ok(_) = self.state {
                     self.state = SingleMachineMonitoringState::GracePeriod(
-                        std::time::SystemTime::now()
-                            + std::time::Duration::from_secs(self.config.grace_period),
+                        self.now() + std::time::Duration::from_secs(self.config.grace_period),
                     );
-                    self.say("Watchdog failed to write the current time to the guest into /tmp/watchdog_current_unix_time. Grace period started").await;
+                    self.say(config::EventKind::GracePeriod, template, &[("path", path)])
+                        .await;
                 }
             } else {
                 // Write was successful,
-                // now read the reset time from the guest.
-                match self
-                    .api
-                    .guest_agent_read_file(&self.config, "/tmp/watchdog_reset_after")
-                    .await
-                {
-                    Err(why) => {
-                        println!(
-                            "VMID {} read_file /tmp/watchdog_reset_after failed: {}",
-                            self.config.vmid, why
-                        );
-
-                        // Failed reads move to the grace period immediately.
-                        if let SingleMachineMonitoringState::Ok(_) = self.state {
-                            self.state = SingleMachineMonitoringState::GracePeriod(
-                                std::time::SystemTime::now()
-                                    + std::time::Duration::from_secs(self.config.grace_period),
-                            );
-
-                            self.say("Watchdog failed to read the reset time from the guest into /tmp/watchdog_reset_after. Perhaps the file doesn't exist? Grace period started").await;
-                        }
-                    }
-                    Ok(reset_time) => {
-                        match reset_time.trim().parse::<u64>() {
-                            Err(why) => {
-                                tracing::info!(
-                                    "VMID {} failed to parse reset time: {}",
-                                    self.config.vmid,
-                                    why
-                                );
-
-                                // Failed parses move to the grace period immediately.
-                                if let SingleMachineMonitoringState::Ok(_) = self.state {
-                                    self.state = SingleMachineMonitoringState::GracePeriod(
-                                        std::time::SystemTime::now()
-                                            + std::time::Duration::from_secs(
-                                                self.config.grace_period,
-                                            ),
-                                    );
-                                    self.say("Watchdog failed to parse /tmp/watchdog_reset_after as a Unix time. Grace period started").await;
-                                    self.say(&format!(
-                                        "The current text in /tmp/watchdog_reset_after is: \n\n{}",
-                                        &reset_time,
-                                    ))
-                                    .await;
-                                }
-                            }
-
-                            Ok(reset_time) => {
-                                // The machine has successfully given us a reset time.
-                                let reset_time = std::time::SystemTime::UNIX_EPOCH
-                                    + std::time::Duration::from_secs(reset_time);
-
-                                // How many seconds until the reset time?
-                                let seconds_until_reset = reset_time
-                                    .duration_since(std::time::SystemTime::now())
-                                    .unwrap_or_default()
-                                    .as_secs();
-
-                                // If too many, then it's in the TooFar state.
-                                if seconds_until_reset > self.config.max_no_warning_interval {
-                                    if !matches!(
-                                        self.state,
-                                        SingleMachineMonitoringState::TooFar(_)
-                                    ) {
-                                        let reset_time: chrono::DateTime<chrono::Utc> =
-                                            chrono::DateTime::from(reset_time);
-                                        self.say(format!("Machine requested reset at {}, which is too far into the future. This is OK if you are performing manual maintenance.", reset_time).as_str()).await;
-                                    }
-                                    self.state = SingleMachineMonitoringState::TooFar(reset_time);
-                                }
-                                // Otherwise, if the time is in the future, then it's in the Ok state.
-                                else if seconds_until_reset > 0 {
-                                    if !matches!(self.state, SingleMachineMonitoringState::Ok(_)) {
-                                        self.say("Machine is OK").await;
-                                    }
-                                    self.state = SingleMachineMonitoringState::Ok(reset_time);
-                                }
-                            }
-                        }
+                // now read every heartbeat from the guest.
+                read_all_required = true;
+                for index in 0..self.heartbeats.len() {
+                    match self.read_heartbeat(index).await {
+                        Some(false) if self.heartbeats[index].required => read_all_required = false,
+                        Some(_) => {}
+                        None => return,
                     }
                 }
             }
         }
 
-        // If the Ok time is in the past,
-        // then move it to the grace period.
-        if let SingleMachineMonitoringState::Ok(reset_time) = self.state {
-            if reset_time <= std::time::SystemTime::now() {
-                self.state = SingleMachineMonitoringState::GracePeriod(
-                    std::time::SystemTime::now()
-                        + std::time::Duration::from_secs(self.config.grace_period),
-                );
-
-                let reset_time: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(reset_time);
-                self.say(&format!("Machine has not updated its /tmp/watchdog_reset_after in a while (last update was at {reset_time}). Grace period started"))
+        for index in 0..self.heartbeats.len() {
+            match self.heartbeats[index].state {
+                // If the Ok time is in the past,
+                // then the heartbeat has lapsed.
+                HeartbeatState::Ok(reset_time) if reset_time <= self.now() => {
+                    let reset_time: chrono::DateTime<chrono::Utc> =
+                        chrono::DateTime::from(reset_time);
+                    self.lapse(
+                        index,
+                        messages::HEARTBEAT_NOT_UPDATED,
+                        &[("reset_time", reset_time.to_string())],
+                    )
                     .await;
+                }
+                // If there's still no data,
+                // then we haven't yet been able to read a value,
+                // so the heartbeat lapses immediately,
+                // unless the guest hasn't had the time to sign one of our nonces yet.
+                HeartbeatState::NoData
+                    if !(self.heartbeats[index].awaiting_nonce && self.warming_up()) =>
+                {
+                    self.lapse(index, messages::HEARTBEAT_UNREADABLE, &[]).await;
+                }
+                _ => {}
             }
         }
 
-        // If the state is NoData,
-        // then that means that we haven't yet been able to read a value,
-        // so we start the grace period immediately.
-        if let SingleMachineMonitoringState::NoData = self.state {
-            self.state = SingleMachineMonitoringState::GracePeriod(
-                std::time::SystemTime::now()
-                    + std::time::Duration::from_secs(self.config.grace_period),
-            );
-            self.say("Could not read the next reset time from the file at /tmp/watchdog_reset_after. Grace period started")
-                .await;
+        if read_all_required {
+            self.update_from_heartbeats().await;
         }
 
         // If the state is GracePeriod,
         // and the reset time is in the past,
         // then move it to the Resetting state.
-        if let SingleMachineMonitoringState::GracePeriod(reset_time) = self.state {
-            if reset_time <= std::time::SystemTime::now() {
+        if let SingleMachineMonitoringState::GracePeriod(reset_time) = self.state
+            && reset_time <= self.now()
+            && !self.acknowledged
+        {
+            self.say(
+                config::EventKind::Reset,
+                messages::GRACE_PERIOD_EXPIRED,
+                &[],
+            )
+            .await;
+
+            if self.dry_run() {
                 self.state = SingleMachineMonitoringState::Resetting(
-                    std::time::SystemTime::now()
-                        + std::time::Duration::from_secs(self.config.reset_duration),
+                    self.now() + std::time::Duration::from_secs(self.config.reset_duration),
                 );
-                self.say("Grace period has expired. Resetting machine now")
-                    .await;
-
-                if self.config.dry_run {
-                    self.say("Dry-run mode: not actually resetting the machine")
-                        .await;
-                } else {
-                    if let Err(why) = self.api.reset_vm(&self.config).await {
-                        self.say(&format!("Failed to reset machine: {}", why.to_string()))
-                            .await;
-                    }
-                }
+                self.say(
+                    config::EventKind::Reset,
+                    messages::DRY_RUN_NOT_RESETTING,
+                    &[],
+                )
+                .await;
+            } else {
+                self.escalate(0).await;
             }
         }
 
         // If the state is GracePeriod,
         // then check thresholds.
-        if let SingleMachineMonitoringState::GracePeriod(reset_time) = self.state {
+        // With a live countdown, `update_countdown` takes care of this instead.
+        if let SingleMachineMonitoringState::GracePeriod(reset_time) = self.state
+            && !self.acknowledged
+            && !self.config.live_countdown
+        {
             let seconds_until_reset = reset_time
-                .duration_since(std::time::SystemTime::now())
+                .duration_since(self.now())
                 .unwrap_or_default()
                 .as_secs();
 
@@ -346,49 +749,1598 @@ impl SingleMachineMonitoring {
                 }
             }
 
-            if let None = self.last_sent_threshold {
+            if self.last_sent_threshold.is_none() {
+                self.last_sent_threshold = Some(closest_without_going_under.0);
+                self.say(
+                    config::EventKind::Countdown,
+                    messages::WILL_RESET_IN,
+                    &[("seconds", closest_without_going_under.0.to_string())],
+                )
+                .await;
+            } else if let Some(last_sent_threshold) = self.last_sent_threshold
+                && last_sent_threshold != closest_without_going_under.0
+            {
                 self.last_sent_threshold = Some(closest_without_going_under.0);
-                self.say(&format!(
-                    "Machine will reset in {} unless the issue is fixed",
-                    closest_without_going_under.1
-                ))
+                self.say(
+                    config::EventKind::Countdown,
+                    messages::WILL_RESET_IN,
+                    &[("seconds", closest_without_going_under.0.to_string())],
+                )
+                .await;
+            }
+        }
+    }
+
+    pub async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Reconfigure(config) => self.reconfigure(*config),
+            Command::Status(reply) => {
+                let mut status = self.state.describe();
+                if let Some(snoozed_until) = self.snoozed_until {
+                    let snoozed_until: chrono::DateTime<chrono::Utc> =
+                        chrono::DateTime::from(snoozed_until);
+                    status += &format!(", snoozed until {snoozed_until}");
+                }
+                if let Some(reason) = self.reason() {
+                    status += &format!(", guest reports: {reason}");
+                }
+                if self.acknowledged {
+                    status += ", acknowledged";
+                }
+                if self.dry_run() {
+                    status += ", dry-run";
+                }
+                let _ = reply.send(status);
+            }
+            Command::Snooze(duration) => {
+                let Some(until) = self.now().checked_add(duration.min(MAX_SNOOZE)) else {
+                    return;
+                };
+                self.snoozed_until = Some(until);
+                let until: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(until);
+                self.say(
+                    config::EventKind::Command,
+                    messages::SNOOZED_UNTIL,
+                    &[("until", until.to_string())],
+                )
                 .await;
-            } else if let Some(last_sent_threshold) = self.last_sent_threshold {
-                if last_sent_threshold != closest_without_going_under.0 {
-                    self.last_sent_threshold = Some(closest_without_going_under.0);
-                    self.say(&format!(
-                        "Machine will reset in {} unless the issue is fixed",
-                        closest_without_going_under.1
-                    ))
+            }
+            Command::Ack => {
+                if let SingleMachineMonitoringState::GracePeriod(_) = self.state {
+                    self.acknowledged = true;
+                    self.say(config::EventKind::Command, messages::ACKNOWLEDGED, &[])
+                        .await;
+                } else {
+                    self.say(
+                        config::EventKind::Command,
+                        messages::NOTHING_TO_ACKNOWLEDGE,
+                        &[],
+                    )
+                    .await;
+                }
+            }
+            Command::Reset => match self.state {
+                SingleMachineMonitoringState::Escalating(..)
+                | SingleMachineMonitoringState::FollowingTask { .. }
+                | SingleMachineMonitoringState::Resetting(_) => {
+                    self.say(
+                        config::EventKind::Command,
+                        messages::RESET_REQUESTED_ALREADY_RESETTING,
+                        &[],
+                    )
+                    .await;
+                }
+                SingleMachineMonitoringState::PowerOff => {
+                    self.say(
+                        config::EventKind::Command,
+                        messages::RESET_REQUESTED_POWERED_OFF,
+                        &[],
+                    )
                     .await;
                 }
+                _ => {
+                    self.say(config::EventKind::Command, messages::RESET_REQUESTED, &[])
+                        .await;
+                    if self.dry_run() {
+                        self.say(
+                            config::EventKind::Command,
+                            messages::DRY_RUN_NOT_RESETTING,
+                            &[],
+                        )
+                        .await;
+                    } else {
+                        self.escalate(0).await;
+                    }
+                }
+            },
+            Command::DryRun(dry_run) => {
+                self.dry_run_override = Some(dry_run);
+                self.say(
+                    config::EventKind::Command,
+                    if dry_run {
+                        messages::DRY_RUN_ON
+                    } else {
+                        messages::DRY_RUN_OFF
+                    },
+                    &[],
+                )
+                .await;
+            }
+        }
+        self.end_acknowledgement();
+        self.update_countdown().await;
+    }
+
+    /// Keep the live countdown message in sync with the grace period,
+    /// and say how it ended once the grace period is over.
+    async fn update_countdown(&mut self) {
+        if !self.config.live_countdown {
+            return;
+        }
+
+        let counting = match self.state {
+            SingleMachineMonitoringState::GracePeriod(reset_time)
+                if !self.acknowledged && self.snoozed_until.is_none() =>
+            {
+                Some(reset_time)
+            }
+            _ => None,
+        };
+
+        match (counting, self.countdown.take()) {
+            (Some(reset_time), countdown) => {
+                let minutes = reset_time
+                    .duration_since(self.now())
+                    .unwrap_or_default()
+                    .as_secs()
+                    .div_ceil(60);
+                let event = if minutes <= 1 {
+                    self.event(
+                        config::EventKind::Countdown,
+                        messages::WILL_RESET_IN_UNDER_A_MINUTE,
+                        &[],
+                    )
+                } else {
+                    self.event(
+                        config::EventKind::Countdown,
+                        messages::WILL_RESET_IN,
+                        &[("seconds", (minutes * 60).to_string())],
+                    )
+                };
+
+                let live = match countdown {
+                    None => self.notifiers.send_live(&event).await,
+                    Some((live, old_minutes)) => {
+                        if old_minutes != minutes {
+                            self.notifiers.edit_live(&live, &event).await;
+                        }
+                        live
+                    }
+                };
+                self.countdown = Some((live, minutes));
+            }
+            (None, Some((live, _))) => {
+                let text = match self.state {
+                    SingleMachineMonitoringState::Ok(_)
+                    | SingleMachineMonitoringState::TooFar(_) => messages::COUNTDOWN_RECOVERED,
+                    SingleMachineMonitoringState::Resetting(_) if self.dry_run() => {
+                        messages::COUNTDOWN_RESET_SKIPPED
+                    }
+                    SingleMachineMonitoringState::Resetting(_) => {
+                        messages::COUNTDOWN_RESET_PERFORMED
+                    }
+                    SingleMachineMonitoringState::Escalating(..)
+                    | SingleMachineMonitoringState::FollowingTask { .. } => {
+                        messages::COUNTDOWN_ESCALATING
+                    }
+                    SingleMachineMonitoringState::ResetFailed(_) => {
+                        messages::COUNTDOWN_RESET_FAILED
+                    }
+                    SingleMachineMonitoringState::PowerOff => messages::COUNTDOWN_POWERED_OFF,
+                    _ if self.acknowledged => messages::COUNTDOWN_ACKNOWLEDGED,
+                    _ if self.snoozed_until.is_some() => messages::COUNTDOWN_SNOOZED,
+                    _ => messages::COUNTDOWN_STOPPED,
+                };
+                self.notifiers
+                    .edit_live(&live, &self.event(config::EventKind::Countdown, text, &[]))
+                    .await;
             }
+            (None, None) => {}
+        }
+    }
+
+    /// Start the machine after a graceful escalation step shut it down,
+    /// and follow the start task like the other power actions.
+    async fn start_after_shutdown(&mut self) {
+        match self.api.start_vm(&self.config).await {
+            Ok(upid) => self.follow_task(upid, TaskPurpose::StartAfterShutdown),
+            Err(why) => self.start_failed(why.to_string()).await,
         }
     }
 
-    pub async fn say(&self, message: &str) {
-        tracing::info!("MSG: {}", message);
+    /// The machine is down because of us, so keep trying to start it.
+    async fn start_failed(&mut self, error: String) {
+        self.start_failed = true;
+        let retry_time = self.now() + std::time::Duration::from_secs(self.config.grace_period);
+        let retry_time_utc: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(retry_time);
+        self.say(
+            config::EventKind::ResetFailed,
+            messages::START_FAILED,
+            &[("error", error), ("retry_time", retry_time_utc.to_string())],
+        )
+        .await;
+        self.state = SingleMachineMonitoringState::ResetFailed(retry_time);
+    }
 
-        if let (Some(token), Some(chat_id)) = (
-            &self.config.telegram_bot_token,
-            &self.config.telegram_chat_id,
-        ) {
-            let message = format!(
-                "VMID {} ({}): {}",
-                self.config.vmid, self.config.friendly_name, message
-            );
+    /// Proxmox accepting a request doesn't mean it worked,
+    /// so follow its task on the next ticks.
+    /// Waiting for it here would hold up commands until it's done.
+    fn follow_task(&mut self, upid: String, purpose: TaskPurpose) {
+        self.state = SingleMachineMonitoringState::FollowingTask {
+            upid,
+            purpose,
+            deadline: self.now() + std::time::Duration::from_secs(self.config.task_timeout),
+        };
+    }
+
+    /// Look at the task we are following,
+    /// and move on once it has ended or we have waited long enough.
+    async fn check_task(&mut self) {
+        let SingleMachineMonitoringState::FollowingTask {
+            upid,
+            purpose,
+            deadline,
+        } = &self.state
+        else {
+            return;
+        };
+        let (upid, purpose) = (upid.clone(), *purpose);
+        let give_up = self.now() >= *deadline;
 
-            let url = format!("https://api.telegram.org/bot{token}/sendMessage");
+        let outcome = match self.api.check_task(&upid, give_up).await {
+            Ok(None) => {
+                tracing::debug!("Task {} is still running", upid);
+                return;
+            }
+            Ok(Some(outcome)) => Ok(outcome),
+            Err(why) if !give_up => {
+                tracing::warn!("Failed to check task {}: {}", upid, why);
+                return;
+            }
+            Err(why) => Err(why),
+        };
 
-            let res = self
-                .tg_client
-                .post(url)
-                .json(&serde_json::json!({"chat_id": chat_id, "text": message}))
-                .send()
+        match (purpose, outcome) {
+            (TaskPurpose::Stop(step), Ok(outcome)) if outcome.succeeded() => {
+                // The machine is fully down, so it can be started again.
+                match self.api.start_vm(&self.config).await {
+                    Ok(upid) => self.follow_task(upid, TaskPurpose::Restart(step)),
+                    Err(why) => self.step_failed(step, why.to_string()).await,
+                }
+                return;
+            }
+            (TaskPurpose::Restart(_), Ok(outcome)) if outcome.succeeded() => {
+                self.say(
+                    config::EventKind::Reset,
+                    messages::RESET_TASK_DONE,
+                    &[("outcome", outcome.to_string())],
+                )
+                .await;
+            }
+            (TaskPurpose::StartAfterShutdown, Ok(outcome)) if outcome.succeeded() => {}
+            (TaskPurpose::StartAfterShutdown, Ok(outcome)) => {
+                self.start_failed(format!("task {outcome}")).await;
+                return;
+            }
+            (TaskPurpose::Stop(step) | TaskPurpose::Restart(step), Ok(outcome)) => {
+                self.say(
+                    config::EventKind::ResetFailed,
+                    messages::STEP_TASK_FAILED,
+                    &[
+                        ("step", (step + 1).to_string()),
+                        ("outcome", outcome.to_string()),
+                    ],
+                )
+                .await;
+                self.escalate(step + 1).await;
+                return;
+            }
+            (TaskPurpose::Stop(step), Err(why)) => {
+                self.step_failed(step, why.to_string()).await;
+                return;
+            }
+            (_, Err(why)) => {
+                self.say(
+                    config::EventKind::ResetFailed,
+                    messages::TASK_UNCONFIRMED,
+                    &[("upid", upid), ("error", why.to_string())],
+                )
                 .await;
-            if let Err(why) = res {
-                println!("Failed to send message: {}", why);
             }
         }
+
+        self.start_failed = false;
+        self.state = SingleMachineMonitoringState::Resetting(
+            self.now() + std::time::Duration::from_secs(self.config.reset_duration),
+        );
+    }
+
+    /// Say that the escalation step failed, and try the next one.
+    async fn step_failed(&mut self, step: usize, error: String) {
+        self.say(
+            config::EventKind::ResetFailed,
+            messages::STEP_FAILED,
+            &[("step", (step + 1).to_string()), ("error", error)],
+        )
+        .await;
+        self.escalate(step + 1).await;
+    }
+
+    /// Run the escalation steps from the given index,
+    /// until one of them is accepted by Proxmox.
+    async fn escalate(&mut self, first_step: usize) {
+        self.start_failed = false;
+        if first_step == 0 {
+            self.before_reset().await;
+
+            self.reset_check = Some(ResetCheck {
+                at: self.now(),
+                uptime_before: self.last_uptime,
+                use_guest_uptime: false,
+            });
+        }
+
+        let steps = self.config.escalation.clone();
+        for (index, step) in steps.iter().enumerate().skip(first_step) {
+            self.say(
+                config::EventKind::Reset,
+                messages::ESCALATION_STEP,
+                &[
+                    ("step", (index + 1).to_string()),
+                    ("steps", steps.len().to_string()),
+                    ("action", step.action.describe().to_string()),
+                ],
+            )
+            .await;
+
+            // Graceful steps are followed by watching the machine go down,
+            // and the others by following their task.
+            // Containers can't be reset, so they are stopped and started instead.
+            let result = match step.action {
+                config::EscalationAction::AgentShutdown => self
+                    .api
+                    .guest_agent_shutdown(&self.config)
+                    .await
+                    .map(|()| None),
+                config::EscalationAction::AcpiShutdown => {
+                    self.api.shutdown_vm(&self.config).await.map(|_| None)
+                }
+                config::EscalationAction::Reset if self.config.kind == config::GuestKind::Qemu => {
+                    self.api
+                        .reset_vm(&self.config)
+                        .await
+                        .map(|upid| Some((upid, TaskPurpose::Restart(index))))
+                }
+                config::EscalationAction::Reset | config::EscalationAction::StopStart => self
+                    .api
+                    .stop_vm(&self.config)
+                    .await
+                    .map(|upid| Some((upid, TaskPurpose::Stop(index)))),
+            };
+
+            match result {
+                Err(why) => {
+                    self.say(
+                        config::EventKind::ResetFailed,
+                        messages::STEP_FAILED,
+                        &[
+                            ("step", (index + 1).to_string()),
+                            ("error", why.to_string()),
+                        ],
+                    )
+                    .await;
+                }
+                Ok(Some((upid, purpose))) => {
+                    if let Some(check) = &mut self.reset_check {
+                        check.use_guest_uptime = step.action == config::EscalationAction::Reset
+                            && self.config.kind == config::GuestKind::Qemu;
+                    }
+                    self.follow_task(upid, purpose);
+                    return;
+                }
+                Ok(None) => {
+                    self.state = SingleMachineMonitoringState::Escalating(
+                        index,
+                        self.now() + std::time::Duration::from_secs(step.timeout),
+                    );
+                    return;
+                }
+            }
+        }
+
+        // Nothing worked; try again after another grace period.
+        let retry_time = self.now() + std::time::Duration::from_secs(self.config.grace_period);
+        let retry_time_utc: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(retry_time);
+        self.say(
+            config::EventKind::ResetFailed,
+            messages::ALL_STEPS_FAILED,
+            &[("retry_time", retry_time_utc.to_string())],
+        )
+        .await;
+        self.reset_check = None;
+        self.state = SingleMachineMonitoringState::ResetFailed(retry_time);
+    }
+
+    /// Runs right before the first escalation step,
+    /// to keep evidence of why the machine hung.
+    async fn before_reset(&mut self) {
+        let Some(root) = &self.config.incident_dir else {
+            return;
+        };
+
+        let mut incident = match incident::Incident::collect(&self.api, &self.config, root).await {
+            Ok(incident) => incident,
+            Err(why) => {
+                self.say(
+                    config::EventKind::Diagnostics,
+                    messages::DIAGNOSTICS_FAILED,
+                    &[("error", why.to_string())],
+                )
+                .await;
+                return;
+            }
+        };
+
+        // A hung VM's console often shows a kernel panic or OOM message.
+        if self.config.kind == config::GuestKind::Qemu {
+            match self.take_screenshot(&incident.dir).await {
+                Ok(path) => incident.screenshot = Some(path),
+                Err(why) => incident.errors.push(format!("screenshot: {why}")),
+            }
+            if let Err(why) = incident.save().await {
+                tracing::error!("Failed to save incident record: {}", why);
+            }
+        }
+
+        let dir = incident.dir.display().to_string();
+        if incident.errors.is_empty() {
+            self.say(
+                config::EventKind::Diagnostics,
+                messages::DIAGNOSTICS_COLLECTED,
+                &[("dir", dir)],
+            )
+            .await;
+        } else {
+            self.say(
+                config::EventKind::Diagnostics,
+                messages::DIAGNOSTICS_PARTLY_COLLECTED,
+                &[("dir", dir), ("errors", incident.errors.join("\n"))],
+            )
+            .await;
+        }
+
+        if let Some(screenshot) = &incident.screenshot {
+            self.say_photo(
+                config::EventKind::Diagnostics,
+                messages::SCREENSHOT_CAPTION,
+                screenshot,
+            )
+            .await;
+        }
+    }
+
+    /// Ask QEMU to dump the VGA console into a PNG in the given directory.
+    /// The file is written by the QEMU process,
+    /// so this only works if the VM runs on the same node as the watchdog;
+    /// otherwise the file ends up on the other node, and this fails.
+    /// It also fails unless `proxmox_auth` logs in as root@pam with a password,
+    /// since Proxmox only lets root@pam run monitor commands other than `info`,
+    /// and not even root's API tokens.
+    async fn take_screenshot(
+        &self,
+        dir: &std::path::Path,
+    ) -> Result<std::path::PathBuf, api::Error> {
+        let path = std::path::absolute(dir.join("screen.png")).map_err(api::Error::Local)?;
+        let timeout = std::time::Duration::from_secs(self.config.diagnostics_timeout);
+        match tokio::time::timeout(
+            timeout,
+            self.api.screendump(&self.config, &path.to_string_lossy()),
+        )
+        .await
+        {
+            Ok(Ok(())) => match tokio::fs::metadata(&path).await {
+                Ok(_) => Ok(path),
+                Err(_) => Err(api::Error::CommandFailed(format!(
+                    "screendump did not write {} on this host, is the VM running on another node ({})?",
+                    path.display(),
+                    self.config.node.as_deref().unwrap_or("unknown")
+                ))),
+            },
+            Ok(Err(why)) => Err(why),
+            Err(_) => Err(api::Error::CommandFailed(format!(
+                "screendump timed out after {} seconds",
+                timeout.as_secs()
+            ))),
+        }
+    }
+
+    /// Leaving `Resetting`, for whatever reason, is when we check the reset worked.
+    async fn finish_reset(&mut self) {
+        if let Some(check) = self.reset_check.take() {
+            self.verify_reset(check).await;
+        }
+    }
+
+    /// Check that the guest's uptime has dropped since we reset it,
+    /// and complain if it hasn't.
+    async fn verify_reset(&mut self, check: ResetCheck) {
+        let uptime = if check.use_guest_uptime {
+            match self
+                .api
+                .guest_exec(
+                    &self.config,
+                    self.config.guest_os.uptime_command(),
+                    std::time::Duration::from_secs(10),
+                )
+                .await
+            {
+                Ok(output) => output
+                    .split_whitespace()
+                    .next()
+                    .and_then(|uptime| uptime.parse::<f64>().ok())
+                    .map(|uptime| uptime as u64)
+                    .ok_or_else(|| format!("cannot parse {:?}", shorten(&output))),
+                Err(why) => Err(why.to_string()),
+            }
+        } else {
+            Ok(self.last_uptime)
+        };
+        let uptime = match uptime {
+            Ok(uptime) => uptime,
+            Err(why) => {
+                self.say(
+                    config::EventKind::WatchdogError,
+                    messages::UPTIME_UNREADABLE,
+                    &[("error", why)],
+                )
+                .await;
+                return;
+            }
+        };
+
+        // If the guest rebooted, it can't have been up for longer than it's been since the reset.
+        let since_reset = self
+            .now()
+            .duration_since(check.at)
+            .unwrap_or_default()
+            .as_secs();
+        if uptime > since_reset + UPTIME_SLACK {
+            let vars = [
+                ("uptime", uptime.to_string()),
+                ("uptime_before", check.uptime_before.to_string()),
+                ("since_reset", since_reset.to_string()),
+            ];
+            let template = if check.use_guest_uptime {
+                messages::NO_REBOOT_GUEST_UPTIME
+            } else {
+                messages::NO_REBOOT_HOST_UPTIME
+            };
+            self.say(config::EventKind::ResetFailed, template, &vars)
+                .await;
+        } else {
+            tracing::info!(
+                "Reset verified: guest uptime is {}s, reset was {}s ago",
+                uptime,
+                since_reset
+            );
+        }
+    }
+
+    /// Read one heartbeat, and update what we know about it.
+    /// Returns `None` on host-side failures, which end the tick,
+    /// and otherwise whether the file could be read at all.
+    async fn read_heartbeat(&mut self, index: usize) -> Option<bool> {
+        let (name, path) = {
+            let heartbeat = &self.heartbeats[index];
+            (heartbeat.name.clone(), heartbeat.path.clone())
+        };
+
+        let content = match self.api.guest_agent_read_file(&self.config, &path).await {
+            Err(why)
+                if self
+                    .is_host_side_failure("guest_agent_read_file", &why)
+                    .await =>
+            {
+                return None;
+            }
+            Err(why) => {
+                tracing::info!(
+                    "VMID {} read_file {} failed: {}",
+                    self.config.vmid,
+                    path,
+                    why
+                );
+
+                if let api::Error::AgentNotRunning(_) = why {
+                    // Same as for writes: the agent went away after the ping.
+                    self.ping_fail_count += 1;
+                }
+                // Failed reads make the heartbeat lapse immediately.
+                else if let HeartbeatState::Ok(_) = self.heartbeats[index].state {
+                    self.lapse(index, messages::HEARTBEAT_READ_FAILED, &[])
+                        .await;
+                }
+                return Some(false);
+            }
+            Ok(content) => content,
+        };
+
+        let parsed = heartbeat::Heartbeat::parse(&content);
+        if let (Ok(beat), Some(secret)) = (&parsed, &self.config.heartbeat_secret)
+            && let Err(why) = beat.verify(secret.as_bytes(), |nonce| self.is_fresh(nonce))
+        {
+            tracing::info!(
+                "VMID {} rejected heartbeat {}: {}",
+                self.config.vmid,
+                name,
+                why
+            );
+
+            // Right after the watchdog starts, the guest can't have seen any of our nonces yet.
+            if let heartbeat::SignatureError::StaleNonce = why {
+                self.heartbeats[index].awaiting_nonce = true;
+            }
+            // Rejected heartbeats count as broken ones.
+            if let HeartbeatState::Ok(_) = self.heartbeats[index].state {
+                self.lapse(
+                    index,
+                    messages::HEARTBEAT_REJECTED,
+                    &[("error", why.to_string())],
+                )
+                .await;
+            }
+            return Some(false);
+        }
+        self.heartbeats[index].awaiting_nonce = false;
+
+        match parsed {
+            Err(why) => {
+                tracing::info!(
+                    "VMID {} failed to parse heartbeat {}: {}",
+                    self.config.vmid,
+                    name,
+                    why
+                );
+
+                // Failed parses make the heartbeat lapse immediately.
+                if let HeartbeatState::Ok(_) = self.heartbeats[index].state
+                    && self
+                        .lapse(index, messages::HEARTBEAT_PARSE_FAILED, &[])
+                        .await
+                {
+                    self.say(
+                        config::EventKind::GracePeriod,
+                        messages::CURRENT_HEARTBEAT_TEXT,
+                        &[("path", path), ("content", shorten(&content))],
+                    )
+                    .await;
+                }
+            }
+
+            Ok(beat) if beat.status == heartbeat::Status::Failing => {
+                let reason = beat
+                    .reason()
+                    .map(|reason| shorten(&reason))
+                    .unwrap_or_else(|| "no reason given".to_string());
+                tracing::info!(
+                    "VMID {} reports a problem in heartbeat {}: {}",
+                    self.config.vmid,
+                    name,
+                    reason
+                );
+                self.heartbeats[index].reason = Some(reason.clone());
+
+                // The guest knows best, so the heartbeat lapses right away.
+                if let HeartbeatState::Ok(_) | HeartbeatState::TooFar(_) | HeartbeatState::NoData =
+                    self.heartbeats[index].state
+                {
+                    self.lapse(
+                        index,
+                        messages::HEARTBEAT_REPORTS_PROBLEM,
+                        &[("reason", reason)],
+                    )
+                    .await;
+                }
+            }
+
+            Ok(beat) => {
+                // `parse` only lets OK heartbeats through with a reset time in range.
+                let Some(reset_time) = beat.reset_time() else {
+                    tracing::warn!(
+                        "VMID {} heartbeat {} has no usable reset time",
+                        self.config.vmid,
+                        name
+                    );
+                    return Some(false);
+                };
+
+                // The machine has successfully given us a reset time.
+                let now = self.now();
+                let heartbeat = &mut self.heartbeats[index];
+                heartbeat.reason = None;
+
+                // How many seconds until the reset time?
+                let seconds_until_reset =
+                    reset_time.duration_since(now).unwrap_or_default().as_secs();
+
+                // If too many, then it's in the TooFar state.
+                if seconds_until_reset > heartbeat.max_no_warning_interval {
+                    if !matches!(heartbeat.state, HeartbeatState::TooFar(_)) {
+                        heartbeat.state = HeartbeatState::TooFar(reset_time);
+                        let reset_time: chrono::DateTime<chrono::Utc> =
+                            chrono::DateTime::from(reset_time);
+                        self.say(
+                            config::EventKind::Maintenance,
+                            messages::TOO_FAR,
+                            &[("heartbeat", name), ("reset_time", reset_time.to_string())],
+                        )
+                        .await;
+                    } else {
+                        heartbeat.state = HeartbeatState::TooFar(reset_time);
+                    }
+                }
+                // Otherwise, if the time is in the future, then it's in the Ok state.
+                else if seconds_until_reset > 0 {
+                    heartbeat.state = HeartbeatState::Ok(reset_time);
+                }
+            }
+        }
+
+        Some(true)
+    }
+
+    /// The heartbeat no longer vouches for the guest.
+    /// If the guest was fine until now, a required heartbeat starts the grace period,
+    /// and an optional one is only reported.
+    /// Returns whether anything was said about it.
+    async fn lapse(&mut self, index: usize, template: &str, vars: &[(&str, String)]) -> bool {
+        let now = self.now();
+        let heartbeat = &mut self.heartbeats[index];
+        heartbeat.state = HeartbeatState::Lapsed;
+        if !matches!(
+            self.state,
+            SingleMachineMonitoringState::Ok(_)
+                | SingleMachineMonitoringState::NoData
+                | SingleMachineMonitoringState::TooFar(_)
+        ) {
+            return false;
+        }
+
+        let (kind, consequence) = if heartbeat.required {
+            self.state = SingleMachineMonitoringState::GracePeriod(
+                now + std::time::Duration::from_secs(heartbeat.grace_period),
+            );
+            (
+                config::EventKind::GracePeriod,
+                messages::GRACE_PERIOD_STARTED,
+            )
+        } else {
+            (
+                config::EventKind::HeartbeatLapsed,
+                messages::OPTIONAL_HEARTBEAT_LAPSED,
+            )
+        };
+
+        let mut vars = vars.to_vec();
+        vars.push(("heartbeat", heartbeat.name.clone()));
+        vars.push(("path", heartbeat.path.clone()));
+        vars.push(("consequence", consequence.to_string()));
+        self.say(kind, template, &vars).await;
+        true
+    }
+
+    /// The guest is OK once every required heartbeat is,
+    /// and far in the future if all of them are.
+    async fn update_from_heartbeats(&mut self) {
+        let (mut earliest_ok, mut earliest_too_far) = (None, None);
+        for heartbeat in self
+            .heartbeats
+            .iter()
+            .filter(|heartbeat| heartbeat.required)
+        {
+            let (earliest, reset_time) = match heartbeat.state {
+                HeartbeatState::Ok(reset_time) => (&mut earliest_ok, reset_time),
+                HeartbeatState::TooFar(reset_time) => (&mut earliest_too_far, reset_time),
+                HeartbeatState::NoData | HeartbeatState::Lapsed => return,
+            };
+            *earliest = Some(
+                earliest.map_or(reset_time, |earliest: std::time::SystemTime| {
+                    earliest.min(reset_time)
+                }),
+            );
+        }
+
+        let state = match (earliest_ok, earliest_too_far) {
+            (Some(reset_time), _) => SingleMachineMonitoringState::Ok(reset_time),
+            (None, Some(reset_time)) => SingleMachineMonitoringState::TooFar(reset_time),
+            // Without required heartbeats, only pings count.
+            (None, None) => return,
+        };
+
+        // Heartbeats usually come back before the reset timer is over.
+        if let SingleMachineMonitoringState::Resetting(_) = self.state {
+            self.finish_reset().await;
+        }
+
+        if let SingleMachineMonitoringState::Ok(_) = state {
+            if !matches!(
+                self.state,
+                SingleMachineMonitoringState::Ok(_) | SingleMachineMonitoringState::TooFar(_)
+            ) {
+                self.say(config::EventKind::Recovered, messages::MACHINE_OK, &[])
+                    .await;
+            }
+            self.acknowledged = false;
+        }
+        self.state = state;
+    }
+
+    /// Forget what the guest told us, like after a reset,
+    /// and wait for fresh heartbeats.
+    fn start_over(&mut self) {
+        self.state = SingleMachineMonitoringState::NoData;
+        for heartbeat in &mut self.heartbeats {
+            heartbeat.state = HeartbeatState::NoData;
+            heartbeat.reason = None;
+        }
+    }
+
+    /// Hand the guest a new nonce to sign its heartbeats with, if they are signed.
+    async fn write_nonce(&mut self) -> Result<(), (&'static str, String, api::Error)> {
+        if self.config.heartbeat_secret.is_none() {
+            return Ok(());
+        }
+
+        let nonce = hex::encode(rand::random::<[u8; 16]>());
+        let path = self.config.nonce_path();
+        if let Err(why) = self
+            .api
+            .guest_agent_write_file(&self.config, &path, nonce.as_bytes())
+            .await
+        {
+            return Err((messages::NONCE_WRITE_FAILED, path, why));
+        }
+
+        let now = self.now();
+        self.nonces_since.get_or_insert(now);
+        self.nonces
+            .retain(|(_, issued)| now.duration_since(*issued).unwrap_or_default() < NONCE_LIFETIME);
+        self.nonces.push((nonce, now));
+        Ok(())
+    }
+
+    /// Was this nonce handed out recently enough?
+    fn is_fresh(&self, nonce: &str) -> bool {
+        let now = self.now();
+        self.nonces.iter().any(|(issued_nonce, issued)| {
+            issued_nonce == nonce
+                && now.duration_since(*issued).unwrap_or_default() < NONCE_LIFETIME
+        })
+    }
+
+    /// We have only just started handing out nonces.
+    fn warming_up(&self) -> bool {
+        let now = self.now();
+        self.nonces_since
+            .is_none_or(|since| now.duration_since(since).unwrap_or_default() < NONCE_LIFETIME)
+    }
+
+    /// What the guest says is wrong with it,
+    /// naming the heartbeats if it has several.
+    fn reason(&self) -> Option<String> {
+        let reasons: Vec<String> = self
+            .heartbeats
+            .iter()
+            .filter_map(|heartbeat| {
+                let reason = heartbeat.reason.as_ref()?;
+                Some(if self.heartbeats.len() == 1 {
+                    reason.clone()
+                } else {
+                    format!("{}: {}", heartbeat.name, reason)
+                })
+            })
+            .collect();
+        if reasons.is_empty() {
+            None
+        } else {
+            Some(reasons.join("; "))
+        }
+    }
+
+    /// Errors reaching or authenticating to Proxmox,
+    /// or asking the wrong node about the guest,
+    /// are not the guest's fault, so they must not push it towards a reset.
+    /// Returns true if the error was one of those, after logging it.
+    async fn is_host_side_failure(&mut self, action: &str, why: &api::Error) -> bool {
+        match why {
+            api::Error::Transport(_) => {
+                tracing::error!("{} could not reach Proxmox API: {}", action, why);
+                true
+            }
+            api::Error::Auth(_) => {
+                tracing::error!("{} was rejected by Proxmox API: {}", action, why);
+                if !self.auth_failure_reported {
+                    self.auth_failure_reported = true;
+                    self.say(
+                        config::EventKind::WatchdogError,
+                        messages::AUTH_FAILED,
+                        &[("error", why.to_string())],
+                    )
+                    .await;
+                }
+                true
+            }
+            api::Error::NotFound(_) => {
+                // The guest has probably migrated,
+                // so look it up again on the next tick.
+                tracing::warn!("{} did not find the guest: {}", action, why);
+                if let Some(node) = self.config.node.take() {
+                    self.lost_node = Some(node);
+                }
+                true
+            }
+            api::Error::Local(_) => {
+                tracing::error!("{} could not run a command on the host: {}", action, why);
+                true
+            }
+            api::Error::Config(_) => {
+                tracing::error!("{} could not use the Proxmox API: {}", action, why);
+                true
+            }
+            api::Error::Status { .. }
+            | api::Error::AgentNotRunning(_)
+            | api::Error::Malformed(_)
+            | api::Error::CommandFailed(_) => false,
+        }
+    }
+
+    /// Send an image to the chat, like the console screenshot.
+    pub async fn say_photo(&self, kind: config::EventKind, caption: &str, path: &std::path::Path) {
+        tracing::info!("PHOTO: {} ({})", caption, path.display());
+        self.notifiers
+            .send(&notify::Event {
+                attachment: Some(path.to_path_buf()),
+                ..self.event(kind, caption, &[])
+            })
+            .await;
+    }
+
+    /// Tell people about something.
+    /// `template` is the English text, with `{{name}}` for each of `vars`.
+    pub async fn say(&self, kind: config::EventKind, template: &str, vars: &[(&str, String)]) {
+        tracing::info!("MSG: [{:?}] {} {:?}", kind, template, vars);
+        self.notifiers.send(&self.event(kind, template, vars)).await;
+    }
+
+    /// The event's own `vars`, plus what every template can use:
+    /// `vmid`, `friendly_name`, `node`, `state` and `deadline`.
+    fn event(
+        &self,
+        kind: config::EventKind,
+        template: &str,
+        vars: &[(&str, String)],
+    ) -> notify::Event {
+        let mut all_vars = std::collections::BTreeMap::new();
+        all_vars.insert("vmid".to_string(), self.config.vmid.clone());
+        all_vars.insert(
+            "friendly_name".to_string(),
+            self.config.friendly_name.clone(),
+        );
+        all_vars.insert(
+            "node".to_string(),
+            self.config.node.clone().unwrap_or_default(),
+        );
+        all_vars.insert("state".to_string(), self.state.describe());
+        if let Some(deadline) = self.state.deadline() {
+            let deadline: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(deadline);
+            all_vars.insert("deadline".to_string(), deadline.to_string());
+        }
+        if let Some(reason) = self.reason() {
+            all_vars.insert("reason".to_string(), reason);
+        }
+        for (name, value) in vars {
+            all_vars.insert(name.to_string(), value.clone());
+        }
+
+        notify::Event {
+            vmid: self.config.vmid.clone(),
+            friendly_name: self.config.friendly_name.clone(),
+            kind,
+            severity: kind.severity(),
+            template: template.to_string(),
+            vars: all_vars,
+            attachment: None,
+        }
+    }
+}
+
+/// Cut text from the guest down to `MAX_GUEST_TEXT` bytes, marking where it was cut.
+fn shorten(text: &str) -> String {
+    if text.len() <= MAX_GUEST_TEXT {
+        return text.to_string();
+    }
+    let mut end = MAX_GUEST_TEXT;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &text[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    thread_local! {
+        static NOW: std::cell::Cell<std::time::SystemTime> = std::cell::Cell::new(
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_800_000_000),
+        );
+    }
+
+    /// The monitor's clock in tests, which only moves with `advance`.
+    fn test_now() -> std::time::SystemTime {
+        NOW.with(|now| now.get())
+    }
+
+    fn advance(seconds: u64) {
+        NOW.with(|now| now.set(now.get() + std::time::Duration::from_secs(seconds)));
+    }
+
+    const UPID: &str = "UPID:pve:00001234:00005678:6B49D200:qmreset:100:watchdog:";
+
+    /// A stand-in Proxmox API.
+    /// Answers `METHOD /path` with the `data` set for it, or a 500 if there is none,
+    /// and keeps every request it saw.
+    #[derive(Clone)]
+    struct FakeProxmox {
+        url: String,
+        responses: Arc<Mutex<std::collections::HashMap<String, serde_json::Value>>>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeProxmox {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let fake = FakeProxmox {
+                url: format!("http://{}", listener.local_addr().unwrap()),
+                responses: Default::default(),
+                requests: Default::default(),
+            };
+            let server = fake.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(server.clone().answer(stream));
+                }
+            });
+            fake
+        }
+
+        async fn answer(self, mut stream: tokio::net::TcpStream) {
+            let mut received = Vec::new();
+            let mut buffer = [0; 4096];
+            let (head, body_start) = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    return;
+                }
+                received.extend_from_slice(&buffer[..read]);
+                if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break (
+                        String::from_utf8_lossy(&received[..end]).into_owned(),
+                        end + 4,
+                    );
+                }
+            };
+            let length: usize = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map(|(_, value)| value.trim().parse().unwrap())
+                .unwrap_or(0);
+            while received.len() < body_start + length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+            }
+
+            let mut request_line = head.lines().next().unwrap().split(' ');
+            let method = request_line.next().unwrap();
+            let path = request_line.next().unwrap();
+            let path = path.split('?').next().unwrap();
+            let request = format!("{method} {}", path.trim_start_matches("/api2/json"));
+            self.requests.lock().unwrap().push(request.clone());
+
+            let data = self.responses.lock().unwrap().get(&request).cloned();
+            let (status, body) = match data {
+                Some(data) => ("200 OK", serde_json::json!({ "data": data })),
+                None => (
+                    "500 Internal Server Error",
+                    serde_json::json!({ "message": "not stubbed" }),
+                ),
+            };
+            let body = body.to_string();
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+
+        fn respond(&self, request: &str, data: serde_json::Value) {
+            self.responses
+                .lock()
+                .unwrap()
+                .insert(request.to_string(), data);
+        }
+
+        fn running(&self, running: bool) {
+            let status = if running { "running" } else { "stopped" };
+            self.respond(
+                "GET /nodes/pve/qemu/100/status/current",
+                serde_json::json!({ "status": status, "uptime": 1000 }),
+            );
+        }
+
+        /// Let `action`, like `reset`, start a task that has already finished with `exit_status`.
+        fn task(&self, action: &str, exit_status: &str) {
+            self.respond(
+                &format!("POST /nodes/pve/qemu/100/status/{action}"),
+                serde_json::json!(UPID),
+            );
+            self.respond(
+                &format!("GET /nodes/pve/tasks/{UPID}/status"),
+                serde_json::json!({ "status": "stopped", "exitstatus": exit_status }),
+            );
+            self.respond(
+                &format!("GET /nodes/pve/tasks/{UPID}/log"),
+                serde_json::json!([]),
+            );
+        }
+
+        /// Everything asked of the guest other than reading its status or pinging it.
+        fn actions(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.starts_with("POST") && !request.ends_with("/agent/ping"))
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn monitor(
+        proxmox: &FakeProxmox,
+        settings: serde_json::Value,
+    ) -> (SingleMachineMonitoring, Arc<notify::tests::Recorder>) {
+        let mut config = serde_json::json!({
+            "node": "pve",
+            "vmid": "100",
+            "friendly_name": "web",
+            "max_no_warning_interval": 600,
+            "grace_period": 300,
+            "reset_duration": 120,
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        let api = api::Api::from_config(&config::ProxmoxAuth {
+            url: proxmox.url.clone(),
+            user: None,
+            password: None,
+            api_token: Some(config::ApiToken {
+                id: "watchdog@pve!test".to_string(),
+                secret: "secret".to_string(),
+            }),
+            allow_invalid_cert: false,
+        })
+        .unwrap();
+        let (notifiers, recorder) = notify::tests::recording();
+        let monitor = SingleMachineMonitoring {
+            clock: test_now,
+            ..SingleMachineMonitoring::new(api, serde_json::from_value(config).unwrap(), notifiers)
+        };
+        (monitor, recorder)
+    }
+
+    /// Put the monitor into a grace period that has just run out.
+    fn grace_period_over(monitor: &mut SingleMachineMonitoring) {
+        monitor.state = SingleMachineMonitoringState::GracePeriod(test_now());
+        advance(1);
+    }
+
+    fn ladder() -> serde_json::Value {
+        serde_json::json!({ "escalation": [
+            { "action": "agent_shutdown", "timeout": 60 },
+            { "action": "acpi_shutdown", "timeout": 60 },
+            { "action": "reset" },
+        ]})
+    }
+
+    #[tokio::test]
+    async fn escalation_goes_up_the_ladder() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.respond(
+            "POST /nodes/pve/qemu/100/agent/shutdown",
+            serde_json::Value::Null,
+        );
+        proxmox.task("shutdown", "OK");
+        proxmox.task("reset", "OK");
+        let (mut monitor, recorder) = monitor(&proxmox, ladder());
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Escalating(0, deadline) if deadline == test_now() + std::time::Duration::from_secs(60)
+        ));
+        assert_eq!(
+            proxmox.actions(),
+            ["POST /nodes/pve/qemu/100/agent/shutdown"]
+        );
+
+        // The step gets its whole timeout before the next one is tried.
+        advance(59);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Escalating(0, _)
+        ));
+        assert_eq!(proxmox.actions().len(), 1);
+
+        advance(1);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Escalating(1, _)
+        ));
+        assert_eq!(
+            proxmox.actions()[1],
+            "POST /nodes/pve/qemu/100/status/shutdown"
+        );
+        assert!(
+            recorder
+                .texts()
+                .contains(&"Escalation step 1 did not shut the machine down in time".to_string())
+        );
+
+        advance(60);
+        monitor.tick().await;
+        assert!(matches!(
+            &monitor.state,
+            SingleMachineMonitoringState::FollowingTask { upid, purpose: TaskPurpose::Restart(2), .. } if upid == UPID
+        ));
+        assert_eq!(
+            proxmox.actions()[2],
+            "POST /nodes/pve/qemu/100/status/reset"
+        );
+
+        advance(5);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(_)
+        ));
+        assert_eq!(proxmox.actions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_step_falls_through_to_the_next() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        // `agent/shutdown` is not stubbed, so it fails.
+        proxmox.task("shutdown", "OK");
+        let (mut monitor, recorder) = monitor(&proxmox, ladder());
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Escalating(1, _)
+        ));
+        assert_eq!(
+            proxmox.actions(),
+            [
+                "POST /nodes/pve/qemu/100/agent/shutdown",
+                "POST /nodes/pve/qemu/100/status/shutdown",
+            ]
+        );
+        assert!(
+            recorder
+                .texts()
+                .iter()
+                .any(|text| text.starts_with("Escalation step 1 failed"))
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_followed_by_a_start() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.respond(
+            "POST /nodes/pve/qemu/100/agent/shutdown",
+            serde_json::Value::Null,
+        );
+        proxmox.task("start", "OK");
+        let (mut monitor, _) = monitor(&proxmox, ladder());
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        proxmox.running(false);
+        advance(10);
+        monitor.tick().await;
+
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::FollowingTask {
+                purpose: TaskPurpose::StartAfterShutdown,
+                ..
+            }
+        ));
+
+        advance(5);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(_)
+        ));
+        assert_eq!(
+            proxmox.actions()[1],
+            "POST /nodes/pve/qemu/100/status/start"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_step_failing_is_a_failed_reset() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        let (mut monitor, _) = monitor(&proxmox, ladder());
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::ResetFailed(retry_time) if retry_time == test_now() + std::time::Duration::from_secs(300)
+        ));
+        assert_eq!(proxmox.actions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_guest_alone() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.respond(
+            "POST /nodes/pve/qemu/100/agent/shutdown",
+            serde_json::Value::Null,
+        );
+        proxmox.task("shutdown", "OK");
+        proxmox.task("reset", "OK");
+        let mut settings = ladder();
+        settings["dry_run"] = serde_json::json!(true);
+        let (mut monitor, recorder) = monitor(&proxmox, settings);
+
+        monitor.handle_command(Command::Reset).await;
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(_)
+        ));
+
+        assert!(proxmox.actions().is_empty());
+        assert!(
+            recorder
+                .texts()
+                .contains(&"Dry-run mode: not actually resetting the machine".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_start_starts_once_stopped() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.task("stop", "OK");
+        proxmox.task("start", "OK");
+        let (mut monitor, _) = monitor(
+            &proxmox,
+            serde_json::json!({ "escalation": [{ "action": "stop_start" }] }),
+        );
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        assert_eq!(proxmox.actions(), ["POST /nodes/pve/qemu/100/status/stop"]);
+
+        // A stopped machine isn't taken for powered off while its task is followed.
+        proxmox.running(false);
+        advance(5);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::FollowingTask {
+                purpose: TaskPurpose::Restart(0),
+                ..
+            }
+        ));
+        assert_eq!(
+            proxmox.actions()[1],
+            "POST /nodes/pve/qemu/100/status/start"
+        );
+
+        advance(5);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_task_is_a_failed_reset() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.task("reset", "VM 100 not running");
+        let (mut monitor, recorder) = monitor(&proxmox, serde_json::json!({}));
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        advance(5);
+        monitor.tick().await;
+
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::ResetFailed(_)
+        ));
+        assert!(recorder.texts().contains(&format!(
+            "Escalation step 1 failed: task {UPID} finished with status: VM 100 not running"
+        )));
+    }
+
+    #[tokio::test]
+    async fn task_that_never_ends_is_a_failed_reset() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.task("reset", "OK");
+        proxmox.respond(
+            &format!("GET /nodes/pve/tasks/{UPID}/status"),
+            serde_json::json!({ "status": "running" }),
+        );
+        let (mut monitor, recorder) = monitor(&proxmox, serde_json::json!({ "task_timeout": 60 }));
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+
+        // Each tick only looks at the task once, so commands are not held up meanwhile.
+        for _ in 0..11 {
+            advance(5);
+            monitor.tick().await;
+            assert!(matches!(
+                monitor.state,
+                SingleMachineMonitoringState::FollowingTask { .. }
+            ));
+        }
+        let (reply, status) = tokio::sync::oneshot::channel();
+        monitor.handle_command(Command::Status(reply)).await;
+        assert!(
+            status
+                .await
+                .unwrap()
+                .starts_with(&format!("waiting for task {UPID}"))
+        );
+
+        advance(5);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::ResetFailed(_)
+        ));
+        assert!(recorder.texts().contains(&format!(
+            "Escalation step 1 failed: task {UPID} did not finish in time"
+        )));
+    }
+
+    #[tokio::test]
+    async fn ack_only_holds_during_a_grace_period() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        let (mut monitor, recorder) = monitor(&proxmox, serde_json::json!({}));
+
+        monitor.handle_command(Command::Ack).await;
+        assert!(!monitor.acknowledged);
+        assert!(recorder.texts().contains(
+            &"Nothing to acknowledge, the machine is not in its grace period".to_string()
+        ));
+
+        monitor.state = SingleMachineMonitoringState::GracePeriod(
+            test_now() + std::time::Duration::from_secs(300),
+        );
+        monitor.handle_command(Command::Ack).await;
+        assert!(monitor.acknowledged);
+        advance(301);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::GracePeriod(_)
+        ));
+        assert!(proxmox.actions().is_empty());
+
+        // Once the grace period is over, the next one ends in a reset again.
+        proxmox.running(false);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::PowerOff
+        ));
+        assert!(!monitor.acknowledged);
+    }
+
+    #[tokio::test]
+    async fn snooze_ending_keeps_a_reset_going() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        let (mut monitor, _) = monitor(&proxmox, serde_json::json!({}));
+        let reset_time = test_now() + std::time::Duration::from_secs(120);
+        monitor.state = SingleMachineMonitoringState::Resetting(reset_time);
+        monitor.reset_check = Some(ResetCheck {
+            at: test_now(),
+            uptime_before: 5000,
+            use_guest_uptime: false,
+        });
+
+        monitor
+            .handle_command(Command::Snooze(std::time::Duration::from_secs(60)))
+            .await;
+        advance(61);
+        monitor.tick().await;
+
+        assert!(monitor.snoozed_until.is_none());
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(time) if time == reset_time
+        ));
+        assert!(monitor.reset_check.is_some());
+    }
+
+    #[tokio::test]
+    async fn dry_run_command_survives_new_settings() {
+        let proxmox = FakeProxmox::start().await;
+        proxmox.running(true);
+        proxmox.task("reset", "OK");
+        let (mut monitor, _) = monitor(&proxmox, serde_json::json!({}));
+
+        monitor.handle_command(Command::DryRun(true)).await;
+        // Like discovery noticing a changed override on the guest.
+        let mut config = monitor.config.clone();
+        config.grace_period = 600;
+        config.dry_run = false;
+        monitor
+            .handle_command(Command::Reconfigure(Box::new(config)))
+            .await;
+
+        grace_period_over(&mut monitor);
+        monitor.tick().await;
+        assert!(matches!(
+            monitor.state,
+            SingleMachineMonitoringState::Resetting(_)
+        ));
+        assert!(proxmox.actions().is_empty());
     }
 }
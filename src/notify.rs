@@ -0,0 +1,1201 @@
+//! Sending messages about guests to people,
+//! through any number of backends at once.
+
+use std::sync::{Arc, Mutex};
+
+use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
+
+use crate::{config, messages, templates};
+
+mod alertmanager;
+mod digest;
+mod discord;
+mod email;
+mod gotify;
+mod matrix;
+mod ntfy;
+mod outbox;
+mod pagerduty;
+mod quiet;
+mod rate_limit;
+mod slack;
+mod telegram;
+mod webhook;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request never got a response.
+    Http(reqwest_middleware::Error),
+
+    /// The service answered with a non-success status.
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+
+    /// Building or sending the email failed.
+    Email(String),
+
+    /// Reading an attachment failed.
+    Io(std::io::Error),
+
+    /// The notifier's configuration can't be used.
+    Config(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(why) => write!(f, "request failed: {why}"),
+            Error::Status { status, body } => write!(f, "server returned {status}: {body}"),
+            Error::Email(why) => write!(f, "failed to send email: {why}"),
+            Error::Io(why) => write!(f, "failed to read attachment: {why}"),
+            Error::Config(why) => write!(f, "invalid notifier config: {why}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Whether sending the same message again can't work either,
+    /// like a text the service rejects as too long, or a missing attachment.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::Status { status, .. } => {
+                status.is_client_error()
+                    && *status != reqwest::StatusCode::REQUEST_TIMEOUT
+                    && *status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Io(_) | Error::Config(_) => true,
+            Error::Http(_) | Error::Email(_) => false,
+        }
+    }
+}
+
+// The URL is left out of request errors, since some services, like Telegram,
+// put the token in it, and errors end up in logs and outbox files.
+impl From<reqwest_middleware::Error> for Error {
+    fn from(value: reqwest_middleware::Error) -> Self {
+        match value {
+            reqwest_middleware::Error::Reqwest(why) => why.into(),
+            other => Error::Http(other),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Error::Http(reqwest_middleware::Error::Reqwest(value.without_url()))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await.unwrap_or_default();
+    Err(Error::Status { status, body })
+}
+
+/// Something that happened to a guest, before it is put into words for each notifier.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub vmid: String,
+    pub friendly_name: String,
+    pub kind: config::EventKind,
+    pub severity: config::Severity,
+
+    /// The English Handlebars template of the text, like `Machine has moved to node {{to}}`.
+    pub template: String,
+
+    /// Values for the template, and for the notifier's own templates.
+    pub vars: std::collections::BTreeMap<String, String>,
+
+    /// An image to attach, like a console screenshot.
+    pub attachment: Option<std::path::PathBuf>,
+}
+
+/// An event rendered for one notifier.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Message {
+    pub vmid: String,
+    pub friendly_name: String,
+
+    /// Where the guest was when this happened, if known.
+    #[serde(default)]
+    pub node: String,
+
+    pub kind: config::EventKind,
+    pub severity: config::Severity,
+
+    /// Who the message is about, like `VMID 100 (web)`.
+    pub subject: String,
+
+    /// What happened, without the subject.
+    pub text: String,
+
+    /// The text with the subject in front, for backends that only take one string.
+    pub full_text: String,
+
+    /// Whether `full_text` comes from the notifier's own template for this kind of event.
+    #[serde(default)]
+    pub templated: bool,
+
+    /// An image to attach, like a console screenshot.
+    pub attachment: Option<std::path::PathBuf>,
+}
+
+impl Message {
+    /// The text for backends that show the subject on its own.
+    /// The notifier's own template replaces the whole text, so it is used if there is one.
+    pub fn body(&self) -> &str {
+        if self.templated {
+            &self.full_text
+        } else {
+            &self.text
+        }
+    }
+
+    /// Like `full_text`, but also says where the attachment is,
+    /// for backends that can't upload it.
+    pub fn full_text_with_attachment_path(&self) -> String {
+        match &self.attachment {
+            Some(path) => format!("{}\n\nAttachment: {}", self.full_text, path.display()),
+            None => self.full_text.clone(),
+        }
+    }
+
+    /// Stays the same for everything about one guest,
+    /// so that alerting services keep it all in one incident.
+    pub fn dedup_key(&self) -> String {
+        format!("proxmox-soft-watchdog-{}", self.vmid)
+    }
+}
+
+/// What a message means to services that track incidents instead of showing text.
+enum Alert {
+    /// Open the guest's incident, or raise it to this severity.
+    Trigger(config::Severity),
+    Resolve,
+}
+
+impl Alert {
+    /// Messages that don't open, escalate or close an incident mean nothing here.
+    fn of(message: &Message) -> Option<Self> {
+        match message.kind {
+            config::EventKind::GracePeriod
+            | config::EventKind::Reset
+            | config::EventKind::ResetFailed => Some(Alert::Trigger(message.severity)),
+            config::EventKind::Recovered => Some(Alert::Resolve),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Notifier: Send + Sync {
+    async fn send(&self, message: &Message) -> Result<(), Error>;
+
+    /// Send a message that can be edited later,
+    /// and return whatever `edit` needs to find it again.
+    /// Backends that can't edit messages just send it, and return `None`.
+    async fn send_editable(&self, message: &Message) -> Result<Option<String>, Error> {
+        self.send(message).await?;
+        Ok(None)
+    }
+
+    /// Replace the text of a message sent with `send_editable`.
+    async fn edit(&self, _id: &str, _message: &Message) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether messages to this notifier may be merged into digests.
+    /// Notifiers that keep each guest apart can't make sense of them.
+    fn batches(&self) -> bool {
+        true
+    }
+}
+
+/// A message that is kept up to date, like the grace period countdown.
+pub struct LiveMessage {
+    /// The message id for each backend that could edit it.
+    ids: Vec<(String, String)>,
+}
+
+const HTTP_CONNECT_TIMEOUT_SECS: u64 = 5;
+const HTTP_TIMEOUT_SECS: u64 = 15;
+/// Screenshots and incident files take longer.
+const HTTP_UPLOAD_TIMEOUT_SECS: u64 = 60;
+
+/// HTTP clients shared by all the backends.
+#[derive(Clone)]
+struct Http {
+    client: reqwest_middleware::ClientWithMiddleware,
+
+    /// Multipart bodies can't be cloned for retries,
+    /// so uploads go through a plain client.
+    upload: reqwest::Client,
+}
+
+impl Http {
+    fn new() -> Result<Self, Error> {
+        // Messages are sent from the monitoring loop, so a notifier that never answers
+        // must not hold up the next tick, or the reset, for long.
+        let client = |timeout| {
+            reqwest::Client::builder()
+                .connect_timeout(std::time::Duration::from_secs(HTTP_CONNECT_TIMEOUT_SECS))
+                .timeout(std::time::Duration::from_secs(timeout))
+                .build()
+                .map_err(|why| Error::Config(format!("cannot build HTTP client: {why}")))
+        };
+        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+        Ok(Self {
+            client: reqwest_middleware::ClientBuilder::new(client(HTTP_TIMEOUT_SECS)?)
+                .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+                .build(),
+            upload: client(HTTP_UPLOAD_TIMEOUT_SECS)?,
+        })
+    }
+}
+
+/// A notifier, and how it wants its messages worded.
+#[derive(Clone)]
+struct Backend {
+    name: String,
+    notifier: Arc<dyn Notifier>,
+    templates: Arc<templates::Templates>,
+    quiet_hours: Option<Arc<quiet::QuietHours>>,
+}
+
+impl Backend {
+    /// The quiet hours this message has to wait for, if any.
+    /// Notifiers that don't batch, like PagerDuty, have schedules of their own,
+    /// and need every trigger and resolve as it happens.
+    fn holding(&self, message: &Message) -> Option<&Arc<quiet::QuietHours>> {
+        self.quiet_hours
+            .as_ref()
+            .filter(|quiet_hours| self.notifier.batches() && quiet_hours.holds(message))
+    }
+}
+
+/// The notifiers one guest's messages go to.
+#[derive(Clone)]
+pub struct Notifiers {
+    backends: Vec<Backend>,
+    routes: Vec<config::Route>,
+    outbox: Option<Arc<outbox::Outbox>>,
+    digest: Option<Arc<digest::Digest>>,
+    rate_limit: Option<Arc<Mutex<rate_limit::TokenBucket>>>,
+    http: Http,
+
+    /// For the Telegram shorthand in `VmConfig`.
+    shorthand_templates: Arc<templates::Templates>,
+}
+
+impl Notifiers {
+    pub fn from_config(config: &config::Config) -> Result<Self, Error> {
+        let http = Http::new()?;
+
+        let backends = config
+            .notifiers
+            .iter()
+            .map(|config| {
+                Ok(Backend {
+                    name: config.name.clone(),
+                    notifier: build(&config.backend, &http)?,
+                    templates: Arc::new(templates::Templates::new(
+                        config.language,
+                        &config.templates,
+                    )?),
+                    quiet_hours: match &config.quiet_hours {
+                        Some(quiet_hours) => Some(Arc::new(quiet::QuietHours::new(quiet_hours)?)),
+                        None => None,
+                    },
+                })
+            })
+            .collect::<Result<Vec<Backend>, Error>>()?;
+
+        let outbox = match &config.outbox {
+            Some(outbox_config) => {
+                let outbox = outbox::Outbox::new(outbox_config).map_err(|why| {
+                    Error::Config(format!("cannot create outbox directory: {why}"))
+                })?;
+                for backend in &backends {
+                    outbox.register(backend);
+                }
+                Some(Arc::new(outbox))
+            }
+            None => None,
+        };
+
+        let digest = config
+            .digest
+            .as_ref()
+            .map(|digest_config| Arc::new(digest::Digest::new(digest_config, outbox.clone())));
+
+        Ok(Self {
+            backends,
+            routes: Vec::new(),
+            outbox,
+            digest,
+            rate_limit: None,
+            http,
+            shorthand_templates: Arc::new(templates::Templates::new(
+                config.language,
+                &config.templates,
+            )?),
+        })
+    }
+
+    /// Delivers queued messages, if there is an outbox.
+    /// Needs to be spawned once.
+    pub fn outbox_sender(&self) -> Option<impl std::future::Future<Output = ()> + use<>> {
+        self.outbox.clone().map(|outbox| outbox.run())
+    }
+
+    /// Pick the notifiers this guest's messages should go to.
+    pub fn for_vm(&self, vm_config: &config::VmConfig) -> Self {
+        let mut backends: Vec<_> = self
+            .backends
+            .iter()
+            .filter(|backend| match &vm_config.notifiers {
+                Some(names) => names.contains(&backend.name),
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        if let (Some(bot_token), Some(chat_id)) =
+            (&vm_config.telegram_bot_token, &vm_config.telegram_chat_id)
+        {
+            let backend = Backend {
+                name: format!("telegram-{}", vm_config.vmid),
+                notifier: Arc::new(telegram::Telegram::new(
+                    self.http.clone(),
+                    vm_config.telegram_api_url.clone(),
+                    bot_token.clone(),
+                    chat_id.clone(),
+                )),
+                templates: self.shorthand_templates.clone(),
+                quiet_hours: None,
+            };
+            if let Some(outbox) = &self.outbox {
+                outbox.register(&backend);
+            }
+            backends.push(backend);
+        }
+
+        Self {
+            backends,
+            routes: vm_config.routes.clone(),
+            outbox: self.outbox.clone(),
+            digest: self.digest.clone(),
+            rate_limit: vm_config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| Arc::new(Mutex::new(rate_limit::TokenBucket::new(rate_limit)))),
+            http: self.http.clone(),
+            shorthand_templates: self.shorthand_templates.clone(),
+        }
+    }
+
+    /// The notifiers that should get this event, according to the routes.
+    fn routed(&self, event: &Event) -> impl Iterator<Item = &Backend> {
+        self.backends.iter().filter(|backend| {
+            self.routes.is_empty()
+                || self.routes.iter().any(|route| {
+                    route.notifiers.contains(&backend.name)
+                        && route.matches(event.kind, event.severity)
+                })
+        })
+    }
+
+    /// Whether the guest may send this event now to the notifiers that batch.
+    /// Critical events always may. When an event gets through after some were dropped,
+    /// a note about how many goes out first.
+    ///
+    /// Notifiers that don't batch, like PagerDuty, need every trigger and resolve,
+    /// so they are not rate limited, and events only for them don't use up the limit.
+    async fn within_rate_limit(&self, event: &Event) -> bool {
+        let Some(bucket) = &self.rate_limit else {
+            return true;
+        };
+        if event.severity == config::Severity::Critical
+            || !self.routed(event).any(|backend| backend.notifier.batches())
+        {
+            return true;
+        }
+
+        let suppressed = {
+            let mut bucket = bucket.lock().unwrap();
+            if !bucket.take() {
+                bucket.suppressed += 1;
+                tracing::warn!(
+                    "VMID {} is over its rate limit, not sending: {}",
+                    event.vmid,
+                    event.template
+                );
+                return false;
+            }
+            std::mem::take(&mut bucket.suppressed)
+        };
+
+        if suppressed > 0 {
+            let mut notice = event.clone();
+            notice.template = messages::RATE_LIMITED_HEADING.to_string();
+            notice.vars.insert("count".into(), suppressed.to_string());
+            notice.attachment = None;
+            self.dispatch(
+                &notice,
+                self.routed(&notice)
+                    .filter(|backend| backend.notifier.batches()),
+            )
+            .await;
+        }
+        true
+    }
+
+    /// Send the message to every notifier,
+    /// or hold it for the digest if messages are batched
+    /// or the notifier is in its quiet hours.
+    pub async fn send(&self, event: &Event) {
+        let allowed = self.within_rate_limit(event).await;
+        self.dispatch(
+            event,
+            self.routed(event)
+                .filter(|backend| allowed || !backend.notifier.batches()),
+        )
+        .await;
+    }
+
+    async fn dispatch<'a>(&'a self, event: &Event, backends: impl Iterator<Item = &'a Backend>) {
+        for backend in backends {
+            let message = backend.templates.render(event);
+            if let Some(quiet_hours) = backend.holding(&message) {
+                quiet_hours
+                    .hold(backend, self.outbox.as_deref(), message)
+                    .await;
+                continue;
+            }
+            if let Some(digest) = &self.digest
+                && backend.notifier.batches()
+                && digest.batches(&message)
+            {
+                digest.add(backend, message).await;
+                continue;
+            }
+            deliver(self.outbox.as_deref(), backend, message).await;
+        }
+    }
+
+    /// Send a message to every notifier, and remember it so it can be edited.
+    /// Live messages are only useful while they're current, so they skip the outbox,
+    /// and notifiers in their quiet hours don't get them at all.
+    pub async fn send_live(&self, event: &Event) -> LiveMessage {
+        let mut ids = Vec::new();
+        let allowed = self.within_rate_limit(event).await;
+        for backend in self
+            .routed(event)
+            .filter(|backend| allowed || !backend.notifier.batches())
+        {
+            let message = backend.templates.render(event);
+            if backend.holding(&message).is_some() {
+                continue;
+            }
+            match backend.notifier.send_editable(&message).await {
+                Ok(Some(id)) => ids.push((backend.name.clone(), id)),
+                Ok(None) => {}
+                Err(why) => {
+                    tracing::error!("Failed to send message via {}: {}", backend.name, why)
+                }
+            }
+        }
+        LiveMessage { ids }
+    }
+
+    /// Replace the text of a live message, wherever it could be edited.
+    pub async fn edit_live(&self, live: &LiveMessage, event: &Event) {
+        for (name, id) in &live.ids {
+            let Some(backend) = self.backends.iter().find(|backend| &backend.name == name) else {
+                continue;
+            };
+            let message = backend.templates.render(event);
+            if let Err(why) = backend.notifier.edit(id, &message).await {
+                tracing::error!("Failed to edit message via {}: {}", name, why);
+            }
+        }
+    }
+}
+
+/// With an outbox, the message is queued and retried until it gets through.
+/// Without one, failures are only logged, since there is nobody else to tell.
+async fn deliver(outbox: Option<&outbox::Outbox>, backend: &Backend, message: Message) {
+    if let Some(outbox) = outbox {
+        outbox.push(&backend.name, &backend.notifier, message).await;
+        return;
+    }
+    if let Err(why) = backend.notifier.send(&message).await {
+        tracing::error!("Failed to send message via {}: {}", backend.name, why);
+    }
+}
+
+fn build(backend: &config::NotifierBackend, http: &Http) -> Result<Arc<dyn Notifier>, Error> {
+    let http = http.clone();
+    Ok(match backend {
+        config::NotifierBackend::Telegram {
+            bot_token,
+            chat_id,
+            api_url,
+        } => Arc::new(telegram::Telegram::new(
+            http,
+            api_url.clone(),
+            bot_token.clone(),
+            chat_id.clone(),
+        )),
+        config::NotifierBackend::Webhook { url, headers } => {
+            Arc::new(webhook::Webhook::new(http, url.clone(), headers.clone()))
+        }
+        config::NotifierBackend::Email { .. } => Arc::new(email::Email::new(backend)?),
+        config::NotifierBackend::Ntfy { url, topic, token } => Arc::new(ntfy::Ntfy::new(
+            http,
+            url.clone(),
+            topic.clone(),
+            token.clone(),
+        )),
+        config::NotifierBackend::Gotify {
+            url,
+            token,
+            priority,
+        } => Arc::new(gotify::Gotify::new(
+            http,
+            url.clone(),
+            token.clone(),
+            *priority,
+        )),
+        config::NotifierBackend::Matrix {
+            homeserver,
+            access_token,
+            room_id,
+        } => Arc::new(matrix::Matrix::new(
+            http,
+            homeserver,
+            access_token.clone(),
+            room_id,
+        )?),
+        config::NotifierBackend::Slack { webhook_url } => {
+            Arc::new(slack::Slack::new(http, webhook_url.clone()))
+        }
+        config::NotifierBackend::Discord { webhook_url } => {
+            Arc::new(discord::Discord::new(http, webhook_url.clone()))
+        }
+        config::NotifierBackend::Pagerduty { routing_key, url } => Arc::new(
+            pagerduty::PagerDuty::new(http, url.clone(), routing_key.clone()),
+        ),
+        config::NotifierBackend::Alertmanager {
+            url,
+            labels,
+            headers,
+        } => Arc::new(alertmanager::Alertmanager::new(
+            http,
+            url.clone(),
+            labels.clone(),
+            headers.clone(),
+        )),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    /// An event about VMID 100 (web) on node pve1, with the vars monitoring adds.
+    pub fn event(kind: config::EventKind, text: &str) -> Event {
+        Event {
+            vmid: "100".to_string(),
+            friendly_name: "web".to_string(),
+            kind,
+            severity: kind.severity(),
+            template: text.to_string(),
+            vars: [("vmid", "100"), ("friendly_name", "web"), ("node", "pve1")]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            attachment: None,
+        }
+    }
+
+    pub fn message(kind: config::EventKind, text: &str) -> Message {
+        templates::Templates::english().render(&event(kind, text))
+    }
+
+    /// One HTTP request, as a stand-in server saw it.
+    pub struct Request {
+        pub path: String,
+        pub headers: std::collections::HashMap<String, String>,
+        pub body: serde_json::Value,
+    }
+
+    /// Answer the first request to the returned URL with `response` as JSON,
+    /// and hand the request over.
+    pub async fn listen(response: &'static str) -> (String, tokio::task::JoinHandle<Request>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 4096];
+            let (head, body_start) = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+                if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break (
+                        String::from_utf8_lossy(&received[..end]).into_owned(),
+                        end + 4,
+                    );
+                }
+            };
+
+            let mut lines = head.lines();
+            let path = lines
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .unwrap()
+                .to_string();
+            let headers: std::collections::HashMap<String, String> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+                .collect();
+            let length: usize = headers["content-length"].parse().unwrap();
+            while received.len() < body_start + length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                received.extend_from_slice(&buffer[..read]);
+            }
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+
+            Request {
+                path,
+                headers,
+                body: serde_json::from_slice(&received[body_start..body_start + length]).unwrap(),
+            }
+        });
+        (url, request)
+    }
+
+    /// Keeps what it was sent.
+    pub struct Recorder {
+        batches: bool,
+        pub sent: Mutex<Vec<Message>>,
+
+        /// Fail the next message with this status.
+        pub reject: Mutex<Option<reqwest::StatusCode>>,
+    }
+
+    impl Recorder {
+        pub fn new(batches: bool) -> Arc<Self> {
+            Arc::new(Recorder {
+                batches,
+                sent: Mutex::new(Vec::new()),
+                reject: Mutex::new(None),
+            })
+        }
+
+        /// The text of every message so far.
+        pub fn texts(&self) -> Vec<String> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter().map(|message| message.text.clone()).collect()
+        }
+    }
+
+    /// A notifier called `name` that only keeps what it is sent, in English.
+    pub(super) fn backend(name: &str) -> (Backend, Arc<Recorder>) {
+        let recorder = Recorder::new(true);
+        let backend = Backend {
+            name: name.to_string(),
+            notifier: recorder.clone(),
+            templates: Arc::new(templates::Templates::english()),
+            quiet_hours: None,
+        };
+        (backend, recorder)
+    }
+
+    /// Notifiers that only keep what they are sent, in English.
+    pub fn recording() -> (Notifiers, Arc<Recorder>) {
+        let (backend, recorder) = backend("recorder");
+        let notifiers = Notifiers {
+            backends: vec![backend],
+            routes: Vec::new(),
+            outbox: None,
+            digest: None,
+            rate_limit: None,
+            http: Http::new().unwrap(),
+            shorthand_templates: Arc::new(templates::Templates::english()),
+        };
+        (notifiers, recorder)
+    }
+
+    #[async_trait::async_trait]
+    impl Notifier for Recorder {
+        async fn send(&self, message: &Message) -> Result<(), Error> {
+            if let Some(status) = self.reject.lock().unwrap().take() {
+                return Err(Error::Status {
+                    status,
+                    body: String::new(),
+                });
+            }
+            self.sent.lock().unwrap().push(message.clone());
+            Ok(())
+        }
+
+        fn batches(&self) -> bool {
+            self.batches
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit_leaves_incident_notifiers_alone() {
+        let (chat, incidents) = (Recorder::new(true), Recorder::new(false));
+        let backend = |name: &str, notifier: Arc<Recorder>| Backend {
+            name: name.to_string(),
+            notifier,
+            templates: Arc::new(templates::Templates::english()),
+            quiet_hours: None,
+        };
+        let notifiers = Notifiers {
+            backends: vec![
+                backend("chat", chat.clone()),
+                backend("incidents", incidents.clone()),
+            ],
+            routes: Vec::new(),
+            outbox: None,
+            digest: None,
+            rate_limit: Some(Arc::new(Mutex::new(rate_limit::TokenBucket::new(
+                &config::RateLimit {
+                    burst: 1,
+                    per_hour: 0,
+                },
+            )))),
+            http: Http::new().unwrap(),
+            shorthand_templates: Arc::new(templates::Templates::english()),
+        };
+
+        notifiers
+            .send(&event(
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ))
+            .await;
+        notifiers
+            .send(&event(config::EventKind::Recovered, "Machine is OK again"))
+            .await;
+
+        assert_eq!(chat.sent.lock().unwrap().len(), 1);
+        let sent = incidents.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].kind, config::EventKind::Recovered);
+    }
+
+    #[tokio::test]
+    async fn telegram_shorthand_uses_the_global_language() {
+        let (url, request) = listen(r#"{"ok": true, "result": {"message_id": 42}}"#).await;
+        let config: config::Config = serde_json::from_value(serde_json::json!({
+            "proxmox_auth": { "url": "https://pve:8006" },
+            "language": "ru",
+        }))
+        .unwrap();
+        let vm_config: config::VmConfig = serde_json::from_value(serde_json::json!({
+            "node": "pve1",
+            "vmid": "100",
+            "friendly_name": "web",
+            "max_no_warning_interval": 600,
+            "grace_period": 300,
+            "reset_duration": 120,
+            "telegram_bot_token": "TOKEN",
+            "telegram_chat_id": "-100123",
+            "telegram_api_url": url,
+        }))
+        .unwrap();
+        let notifiers = Notifiers::from_config(&config).unwrap().for_vm(&vm_config);
+
+        notifiers
+            .send(&event(
+                config::EventKind::Monitoring,
+                messages::MONITORING_STARTED,
+            ))
+            .await;
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/botTOKEN/sendMessage");
+        assert_eq!(request.body["text"], "ВМ 100 (web): Мониторинг запущен!");
+    }
+
+    #[tokio::test]
+    async fn telegram_sends_the_full_text() {
+        let (url, request) = listen(r#"{"ok": true, "result": {"message_id": 42}}"#).await;
+        let telegram = telegram::Telegram::new(
+            Http::new().unwrap(),
+            url,
+            "TOKEN".to_string(),
+            "-100123".to_string(),
+        );
+
+        let id = telegram
+            .send_editable(&message(
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(id.as_deref(), Some("42"));
+        assert_eq!(request.path, "/botTOKEN/sendMessage");
+        assert_eq!(request.body["chat_id"], "-100123");
+        assert_eq!(request.body["text"], "VMID 100 (web): Grace period started");
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_the_message_with_its_headers() {
+        let (url, request) = listen("{}").await;
+        let webhook = webhook::Webhook::new(
+            Http::new().unwrap(),
+            format!("{url}/hook"),
+            [("Authorization".to_string(), "Bearer secret".to_string())].into(),
+        );
+
+        webhook
+            .send(&message(config::EventKind::Reset, "Resetting machine now"))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        assert_eq!(request.body["vmid"], "100");
+        assert_eq!(request.body["kind"], "reset");
+        assert_eq!(request.body["severity"], "critical");
+        assert_eq!(request.body["subject"], "VMID 100 (web)");
+        assert_eq!(request.body["text"], "Resetting machine now");
+    }
+
+    #[tokio::test]
+    async fn webhook_sends_the_template_override_as_text() {
+        let (url, request) = listen("{}").await;
+        let webhook = webhook::Webhook::new(Http::new().unwrap(), url, Default::default());
+        let templates = templates::Templates::new(
+            config::Language::En,
+            &[(
+                config::EventKind::Reset,
+                "{{friendly_name}} on {{node}}: {{text}}".to_string(),
+            )]
+            .into(),
+        )
+        .unwrap();
+        let message = templates.render(&event(config::EventKind::Reset, "Resetting machine now"));
+
+        webhook.send(&message).await.unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.body["text"], "web on pve1: Resetting machine now");
+    }
+
+    #[tokio::test]
+    async fn pagerduty_triggers_and_resolves_one_incident_per_guest() {
+        let (url, request) = listen(r#"{"status": "success"}"#).await;
+        let pagerduty = pagerduty::PagerDuty::new(Http::new().unwrap(), url, "KEY".to_string());
+        pagerduty
+            .send(&message(
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.body["routing_key"], "KEY");
+        assert_eq!(request.body["event_action"], "trigger");
+        assert_eq!(request.body["dedup_key"], "proxmox-soft-watchdog-100");
+        assert_eq!(request.body["payload"]["severity"], "warning");
+        assert_eq!(request.body["payload"]["source"], "pve1");
+        assert_eq!(
+            request.body["payload"]["summary"],
+            "VMID 100 (web): Grace period started"
+        );
+
+        let (url, request) = listen(r#"{"status": "success"}"#).await;
+        let pagerduty = pagerduty::PagerDuty::new(Http::new().unwrap(), url, "KEY".to_string());
+        pagerduty
+            .send(&message(config::EventKind::Recovered, "Machine is OK"))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.body["event_action"], "resolve");
+        assert_eq!(request.body["dedup_key"], "proxmox-soft-watchdog-100");
+    }
+
+    #[tokio::test]
+    async fn pagerduty_ignores_other_messages() {
+        // Nothing listens here, so any request would fail.
+        let pagerduty = pagerduty::PagerDuty::new(
+            Http::new().unwrap(),
+            "http://127.0.0.1:1".to_string(),
+            "KEY".to_string(),
+        );
+        pagerduty
+            .send(&message(
+                config::EventKind::Monitoring,
+                "Monitoring loop started!",
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn alertmanager_fires_the_alert_of_the_message_severity() {
+        let (url, request) = listen("").await;
+        let alertmanager = alertmanager::Alertmanager::new(
+            Http::new().unwrap(),
+            format!("{url}/"),
+            [("team".to_string(), "infra".to_string())].into(),
+            [("Authorization".to_string(), "Basic abc".to_string())].into(),
+        );
+
+        alertmanager
+            .send(&message(config::EventKind::Reset, "Resetting machine now"))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/api/v2/alerts");
+        assert_eq!(request.headers["authorization"], "Basic abc");
+        let alerts = request.body.as_array().unwrap();
+        assert_eq!(alerts.len(), 2);
+
+        let now = chrono::Utc::now();
+        for alert in alerts {
+            assert_eq!(alert["labels"]["alertname"], "ProxmoxSoftWatchdog");
+            assert_eq!(alert["labels"]["vmid"], "100");
+            assert_eq!(alert["labels"]["team"], "infra");
+            assert_eq!(alert["annotations"]["description"], "Resetting machine now");
+
+            let ends_at: chrono::DateTime<chrono::Utc> =
+                alert["endsAt"].as_str().unwrap().parse().unwrap();
+            // Only the critical alert fires; the warning one ends now.
+            let firing = alert["labels"]["severity"] == "critical";
+            assert_eq!(ends_at > now + chrono::TimeDelta::days(1), firing);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_errors_leave_out_the_url() {
+        // Nothing listens here.
+        let why = reqwest::Client::new()
+            .post("http://127.0.0.1:1/botSECRET/sendMessage")
+            .send()
+            .await
+            .unwrap_err();
+
+        let why = Error::from(why).to_string();
+        assert!(!why.contains("SECRET"), "{why}");
+    }
+
+    #[tokio::test]
+    async fn ntfy_publishes_the_body_with_a_title() {
+        let (url, request) = listen(r#"{"id": "abc"}"#).await;
+        let ntfy = ntfy::Ntfy::new(
+            Http::new().unwrap(),
+            format!("{url}/"),
+            "watchdog".to_string(),
+            Some("tk_123".to_string()),
+        );
+
+        ntfy.send(&message(config::EventKind::Reset, "Resetting machine now"))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.headers["authorization"], "Bearer tk_123");
+        assert_eq!(request.body["topic"], "watchdog");
+        assert_eq!(request.body["title"], "VMID 100 (web)");
+        assert_eq!(request.body["message"], "Resetting machine now");
+    }
+
+    #[tokio::test]
+    async fn gotify_posts_a_message_with_its_priority() {
+        let (url, request) = listen(r#"{"id": 1}"#).await;
+        let gotify = gotify::Gotify::new(Http::new().unwrap(), url, "APPTOKEN".to_string(), 8);
+
+        gotify
+            .send(&message(
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/message");
+        assert_eq!(request.headers["x-gotify-key"], "APPTOKEN");
+        assert_eq!(request.body["title"], "VMID 100 (web)");
+        assert_eq!(
+            request.body["message"],
+            "VMID 100 (web): Grace period started"
+        );
+        assert_eq!(request.body["priority"], 8);
+    }
+
+    #[tokio::test]
+    async fn matrix_sends_each_message_as_a_new_event() {
+        let (url, request) = listen(r#"{"event_id": "$abc"}"#).await;
+        let matrix = matrix::Matrix::new(
+            Http::new().unwrap(),
+            &url,
+            "syt_token".to_string(),
+            "!room:example.org",
+        )
+        .unwrap();
+
+        matrix
+            .send(&message(config::EventKind::Recovered, "Machine is OK"))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert!(
+            request.path.starts_with(
+                "/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/soft-watchdog-"
+            ),
+            "{}",
+            request.path
+        );
+        assert_eq!(request.headers["authorization"], "Bearer syt_token");
+        assert_eq!(request.body["msgtype"], "m.text");
+        assert_eq!(request.body["body"], "VMID 100 (web): Machine is OK");
+    }
+
+    #[tokio::test]
+    async fn slack_posts_the_full_text() {
+        let (url, request) = listen("{}").await;
+        let slack = slack::Slack::new(Http::new().unwrap(), format!("{url}/services/T0/B0/X"));
+
+        let mut message = message(
+            config::EventKind::Diagnostics,
+            "Console right before the reset",
+        );
+        message.attachment = Some("/var/lib/soft-watchdog/screen.png".into());
+        slack.send(&message).await.unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/services/T0/B0/X");
+        assert_eq!(
+            request.body["text"],
+            "VMID 100 (web): Console right before the reset\n\nAttachment: /var/lib/soft-watchdog/screen.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn discord_cuts_long_messages() {
+        let (url, request) = listen("{}").await;
+        let discord =
+            discord::Discord::new(Http::new().unwrap(), format!("{url}/api/webhooks/1/x"));
+
+        discord
+            .send(&message(config::EventKind::GracePeriod, &"a".repeat(3000)))
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap();
+        assert_eq!(request.path, "/api/webhooks/1/x");
+        let content = request.body["content"].as_str().unwrap();
+        assert_eq!(content.chars().count(), 2000);
+        assert!(content.starts_with("VMID 100 (web): aaa"));
+    }
+
+    /// Accept one email over plain SMTP, and hand over the commands and the message.
+    async fn listen_smtp() -> (u16, tokio::task::JoinHandle<(Vec<String>, String)>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mail = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = tokio::io::BufReader::new(read).lines();
+            write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+            let (mut commands, mut data) = (Vec::new(), String::new());
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let verb = line.split(' ').next().unwrap_or_default().to_uppercase();
+                commands.push(line);
+                let reply: &[u8] = match verb.as_str() {
+                    "EHLO" | "HELO" => b"250 localhost\r\n",
+                    "DATA" => {
+                        write.write_all(b"354 go ahead\r\n").await.unwrap();
+                        while let Some(line) = lines.next_line().await.unwrap() {
+                            if line == "." {
+                                break;
+                            }
+                            data += &line;
+                            data += "\n";
+                        }
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        write.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+            (commands, data)
+        });
+        (port, mail)
+    }
+
+    #[tokio::test]
+    async fn email_sends_the_subject_and_body() {
+        let (port, mail) = listen_smtp().await;
+        let email = email::Email::new(&config::NotifierBackend::Email {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: port,
+            smtp_security: config::SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "Watchdog <watchdog@example.org>".to_string(),
+            to: vec!["ops@example.org".to_string()],
+        })
+        .unwrap();
+
+        email
+            .send(&message(config::EventKind::Reset, "Resetting machine now"))
+            .await
+            .unwrap();
+
+        let (commands, data) = mail.await.unwrap();
+        assert!(commands.contains(&"MAIL FROM:<watchdog@example.org>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<ops@example.org>".to_string()));
+        assert!(data.contains("Subject: VMID 100 (web)\n"), "{data}");
+        assert!(data.contains("To: ops@example.org\n"), "{data}");
+        assert!(data.contains("\nResetting machine now\n"), "{data}");
+    }
+}
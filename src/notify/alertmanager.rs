@@ -0,0 +1,93 @@
+use super::{Alert, Error, Http, Message, Notifier, check_status};
+use crate::config;
+
+/// Alertmanager resolves alerts that aren't sent again for a while,
+/// but we only send them when something changes,
+/// so firing alerts say outright how long they last.
+const FIRING_FOR: chrono::TimeDelta = chrono::TimeDelta::days(7);
+
+/// Keeps one Alertmanager alert per guest.
+pub struct Alertmanager {
+    http: Http,
+    url: String,
+    labels: std::collections::BTreeMap<String, String>,
+    headers: std::collections::BTreeMap<String, String>,
+}
+
+impl Alertmanager {
+    pub fn new(
+        http: Http,
+        url: String,
+        labels: std::collections::BTreeMap<String, String>,
+        headers: std::collections::BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            http,
+            url,
+            labels,
+            headers,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Alertmanager {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let firing = match Alert::of(message) {
+            None => return Ok(()),
+            Some(Alert::Trigger(severity)) => Some(severity),
+            Some(Alert::Resolve) => None,
+        };
+
+        // Alertmanager tells alerts apart by their labels, and the severity is one of them,
+        // so every message covers both severities, to end the warning when it becomes critical.
+        let now = chrono::Utc::now();
+        let alerts: Vec<_> = [config::Severity::Warning, config::Severity::Critical]
+            .into_iter()
+            .map(|severity| {
+                let mut labels = self.labels.clone();
+                labels.insert("alertname".into(), "ProxmoxSoftWatchdog".into());
+                labels.insert("dedup_key".into(), message.dedup_key());
+                labels.insert("vmid".into(), message.vmid.clone());
+                labels.insert("friendly_name".into(), message.friendly_name.clone());
+                labels.insert(
+                    "severity".into(),
+                    serde_json::to_value(severity)
+                        .ok()
+                        .and_then(|value| value.as_str().map(str::to_string))
+                        .unwrap_or_default(),
+                );
+
+                let ends_at = if firing == Some(severity) {
+                    now + FIRING_FOR
+                } else {
+                    now
+                };
+                serde_json::json!({
+                    "labels": labels,
+                    "annotations": {
+                        "summary": message.subject,
+                        "description": message.body(),
+                        "node": message.node,
+                    },
+                    "endsAt": ends_at.to_rfc3339(),
+                })
+            })
+            .collect();
+
+        let mut request = self
+            .http
+            .client
+            .post(format!("{}/api/v2/alerts", self.url.trim_end_matches('/')))
+            .json(&alerts);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        check_status(request.send().await?).await?;
+        Ok(())
+    }
+
+    fn batches(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,234 @@
+//! Batches messages that arrive close together into one per notifier,
+//! so that a node going down posts one message instead of one for every guest on it.
+//! With an outbox, the messages wait in it, so that a restart doesn't lose them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{Backend, Message, deliver, outbox};
+use crate::messages;
+
+pub struct Digest {
+    window: std::time::Duration,
+    bypass_critical: bool,
+    outbox: Option<Arc<outbox::Outbox>>,
+
+    /// Messages waiting for their window to end, by notifier name, without an outbox.
+    pending: Mutex<HashMap<String, Vec<Message>>>,
+
+    /// When the current window of each notifier ends, as a Unix time, with an outbox.
+    window_ends: Mutex<HashMap<String, u64>>,
+}
+
+impl Digest {
+    pub fn new(digest: &crate::config::DigestConfig, outbox: Option<Arc<outbox::Outbox>>) -> Self {
+        Self {
+            window: std::time::Duration::from_secs(digest.window),
+            bypass_critical: digest.bypass_critical,
+            outbox,
+            pending: Mutex::new(HashMap::new()),
+            window_ends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Should this message wait for the digest, or go out right away?
+    pub fn batches(&self, message: &Message) -> bool {
+        // Photos don't fit into a digest.
+        message.attachment.is_none()
+            && !(self.bypass_critical && message.severity == crate::config::Severity::Critical)
+    }
+
+    /// Hold the message until the window that it opened, or joined, ends.
+    pub async fn add(self: &Arc<Self>, backend: &Backend, message: Message) {
+        if let Some(outbox) = &self.outbox {
+            let until = {
+                let now = outbox::unix_now();
+                let mut window_ends = self.window_ends.lock().unwrap();
+                let end = window_ends.entry(backend.name.clone()).or_default();
+                if *end <= now {
+                    *end = now + self.window.as_secs();
+                }
+                *end
+            };
+            outbox
+                .hold(
+                    backend,
+                    message,
+                    until,
+                    messages::DIGEST_HEADING,
+                    self.window.as_secs(),
+                )
+                .await;
+            return;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        let messages = pending.entry(backend.name.clone()).or_default();
+        messages.push(message);
+        if messages.len() > 1 {
+            return;
+        }
+
+        let digest = self.clone();
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(digest.window).await;
+            digest.flush(&backend).await;
+        });
+    }
+
+    async fn flush(&self, backend: &Backend) {
+        let Some(messages) = self.pending.lock().unwrap().remove(&backend.name) else {
+            return;
+        };
+
+        let message = if messages.len() == 1 {
+            messages.into_iter().next().expect("checked the length")
+        } else {
+            tracing::info!(
+                "Sending {} messages via {} as one digest",
+                messages.len(),
+                backend.name
+            );
+            backend.templates.render_digest(
+                messages::DIGEST_HEADING,
+                self.window.as_secs(),
+                &messages,
+            )
+        };
+        deliver(None, backend, message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::notify::tests::backend;
+
+    fn digest(bypass_critical: bool) -> Arc<Digest> {
+        Arc::new(Digest::new(
+            &config::DigestConfig {
+                window: 1,
+                bypass_critical,
+            },
+            None,
+        ))
+    }
+
+    fn message(node: &str, kind: config::EventKind, text: &str) -> Message {
+        Message {
+            node: node.to_string(),
+            ..crate::notify::tests::message(kind, text)
+        }
+    }
+
+    /// Long enough for a one-second window to end.
+    async fn window_ends() {
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    }
+
+    #[tokio::test]
+    async fn merges_each_notifiers_messages_by_node_and_kind() {
+        let digest = digest(true);
+        let (chat, chat_sent) = backend("chat");
+        let (log, log_sent) = backend("log");
+
+        for message in [
+            message(
+                "pve2",
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ),
+            message(
+                "pve1",
+                config::EventKind::GracePeriod,
+                "Grace period started",
+            ),
+            message("pve1", config::EventKind::Recovered, "Machine is OK"),
+            message("pve1", config::EventKind::GracePeriod, "Still no heartbeat"),
+        ] {
+            digest.add(&chat, message).await;
+        }
+        digest
+            .add(
+                &log,
+                message("pve1", config::EventKind::Recovered, "Machine is OK"),
+            )
+            .await;
+        assert!(chat_sent.sent.lock().unwrap().is_empty());
+
+        window_ends().await;
+        let chat_sent = chat_sent.sent.lock().unwrap();
+        assert_eq!(chat_sent.len(), 1);
+        assert_eq!(chat_sent[0].subject, "4 events in the last 1 seconds");
+        assert_eq!(
+            chat_sent[0].text,
+            "Node pve1, recovered (1):
+VMID 100 (web): Machine is OK
+
+Node pve1, grace_period (2):
+VMID 100 (web): Grace period started
+VMID 100 (web): Still no heartbeat
+
+Node pve2, grace_period (1):
+VMID 100 (web): Grace period started"
+        );
+
+        // A single message goes out as it is.
+        let log_sent = log_sent.sent.lock().unwrap();
+        assert_eq!(log_sent.len(), 1);
+        assert_eq!(log_sent[0].full_text, "VMID 100 (web): Machine is OK");
+    }
+
+    #[test]
+    fn critical_messages_bypass_the_digest_if_configured() {
+        let reset = message("pve1", config::EventKind::Reset, "Resetting machine now");
+        let grace_period = message(
+            "pve1",
+            config::EventKind::GracePeriod,
+            "Grace period started",
+        );
+
+        assert!(!digest(true).batches(&reset));
+        assert!(digest(true).batches(&grace_period));
+        assert!(digest(false).batches(&reset));
+
+        let photo = Message {
+            attachment: Some("/tmp/screen.png".into()),
+            ..grace_period
+        };
+        assert!(!digest(false).batches(&photo));
+    }
+
+    #[tokio::test]
+    async fn window_starts_over_after_a_flush() {
+        let digest = digest(true);
+        let (chat, sent) = backend("chat");
+
+        digest
+            .add(
+                &chat,
+                message("pve1", config::EventKind::GracePeriod, "First"),
+            )
+            .await;
+        window_ends().await;
+        assert_eq!(sent.sent.lock().unwrap().len(), 1);
+
+        // The next message opens a new window, instead of going out right away.
+        digest
+            .add(
+                &chat,
+                message("pve1", config::EventKind::GracePeriod, "Second"),
+            )
+            .await;
+        assert_eq!(sent.sent.lock().unwrap().len(), 1);
+
+        window_ends().await;
+        let sent = sent.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].text, "Second");
+    }
+}
@@ -0,0 +1,37 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+/// Discord rejects messages longer than this.
+const MAX_CONTENT_CHARS: usize = 2000;
+
+/// Posts to a Discord channel webhook.
+pub struct Discord {
+    http: Http,
+    webhook_url: String,
+}
+
+impl Discord {
+    pub fn new(http: Http, webhook_url: String) -> Self {
+        Self { http, webhook_url }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Discord {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let content: String = message
+            .full_text_with_attachment_path()
+            .chars()
+            .take(MAX_CONTENT_CHARS)
+            .collect();
+
+        let res = self
+            .http
+            .client
+            .post(&self.webhook_url)
+            .json(&serde_json::json!({ "content": content }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+    message::{Attachment, Mailbox, MultiPart, SinglePart, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+
+use super::{Error, Message, Notifier};
+use crate::config;
+
+pub struct Email {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Email {
+    pub fn new(backend: &config::NotifierBackend) -> Result<Self, Error> {
+        let config::NotifierBackend::Email {
+            smtp_host,
+            smtp_port,
+            smtp_security,
+            username,
+            password,
+            from,
+            to,
+        } = backend
+        else {
+            unreachable!("Email::new called with another backend's config");
+        };
+
+        let builder = match smtp_security {
+            config::SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)
+            }
+            config::SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_host),
+            config::SmtpSecurity::None => Ok(
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(smtp_host),
+            ),
+        }
+        .map_err(|why| Error::Config(why.to_string()))?;
+
+        let mut builder = builder.port(*smtp_port);
+        if let (Some(username), Some(password)) = (username, password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let parse = |address: &String| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|why| Error::Config(format!("{address}: {why}")))
+        };
+
+        Ok(Self {
+            transport: builder.build(),
+            from: parse(from)?,
+            to: to.iter().map(parse).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Email {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let mut email = lettre::Message::builder()
+            .from(self.from.clone())
+            .subject(message.subject.clone());
+        for to in &self.to {
+            email = email.to(to.clone());
+        }
+
+        let text = SinglePart::plain(message.body().to_string());
+        let email = match &message.attachment {
+            None => email.singlepart(text),
+            Some(path) => {
+                let content = tokio::fs::read(path).await?;
+                let file_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let attachment = Attachment::new(file_name)
+                    .body(content, ContentType::parse("image/png").unwrap());
+                email.multipart(MultiPart::mixed().singlepart(text).singlepart(attachment))
+            }
+        }
+        .map_err(|why| Error::Email(why.to_string()))?;
+
+        self.transport
+            .send(email)
+            .await
+            .map_err(|why| Error::Email(why.to_string()))?;
+        Ok(())
+    }
+}
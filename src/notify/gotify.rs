@@ -0,0 +1,39 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+pub struct Gotify {
+    http: Http,
+    url: String,
+    token: String,
+    priority: u8,
+}
+
+impl Gotify {
+    pub fn new(http: Http, url: String, token: String, priority: u8) -> Self {
+        Self {
+            http,
+            url,
+            token,
+            priority,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Gotify {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let res = self
+            .http
+            .client
+            .post(format!("{}/message", self.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", &self.token)
+            .json(&serde_json::json!({
+                "title": message.subject,
+                "message": message.full_text_with_attachment_path(),
+                "priority": self.priority,
+            }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+}
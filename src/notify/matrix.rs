@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Error, Http, Message, Notifier, check_status};
+
+pub struct Matrix {
+    http: Http,
+
+    /// `/_matrix/client/v3/rooms/{room_id}/send/m.room.message`,
+    /// to which the transaction ID is appended.
+    send_url: reqwest::Url,
+    access_token: String,
+
+    /// Every event needs its own transaction ID,
+    /// or the homeserver treats it as a retry of an earlier one.
+    next_txn: AtomicU64,
+}
+
+impl Matrix {
+    pub fn new(
+        http: Http,
+        homeserver: &str,
+        access_token: String,
+        room_id: &str,
+    ) -> Result<Self, Error> {
+        let mut send_url = reqwest::Url::parse(homeserver)
+            .map_err(|why| Error::Config(format!("{homeserver}: {why}")))?;
+        send_url
+            .path_segments_mut()
+            .map_err(|()| Error::Config(format!("{homeserver} can't be a base URL")))?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "send",
+                "m.room.message",
+            ]);
+
+        // Start from the current time, so restarts don't reuse transaction IDs.
+        let first_txn = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Ok(Self {
+            http,
+            send_url,
+            access_token,
+            next_txn: AtomicU64::new(first_txn),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Matrix {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let txn = self.next_txn.fetch_add(1, Ordering::Relaxed);
+        let mut url = self.send_url.clone();
+        url.path_segments_mut()
+            .expect("checked in Matrix::new")
+            .push(&format!("soft-watchdog-{txn}"));
+
+        let res = self
+            .http
+            .client
+            .put(url)
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({
+                "msgtype": "m.text",
+                "body": message.full_text_with_attachment_path(),
+            }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+}
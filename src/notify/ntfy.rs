@@ -0,0 +1,63 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+pub struct Ntfy {
+    http: Http,
+    url: String,
+    topic: String,
+    token: Option<String>,
+}
+
+impl Ntfy {
+    pub fn new(http: Http, url: String, topic: String, token: Option<String>) -> Self {
+        Self {
+            http,
+            url,
+            topic,
+            token,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Ntfy {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let url = format!("{}/{}", self.url.trim_end_matches('/'), self.topic);
+
+        // Publishing as JSON to the root URL lets the title and text be any Unicode,
+        // which plain headers can't carry.
+        let mut request =
+            self.http
+                .client
+                .post(self.url.trim_end_matches('/'))
+                .json(&serde_json::json!({
+                    "topic": self.topic,
+                    "title": message.subject,
+                    "message": message.body(),
+                }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        check_status(request.send().await?).await?;
+
+        // Attachments are uploaded as the body of a second message.
+        if let Some(path) = &message.attachment {
+            let content = tokio::fs::read(path).await?;
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let mut request = self
+                .http
+                .upload
+                .put(url)
+                .header("Filename", file_name)
+                .body(content);
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            check_status(request.send().await?).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+//! Messages waiting to be delivered, kept on disk
+//! so that an outage of a notifier, or a restart of the watchdog, doesn't lose them.
+//!
+//! Every message is written to the outbox directory first,
+//! and a background sender delivers them in order for each notifier,
+//! backing off while a notifier keeps failing.
+//!
+//! Messages held for a digest or for quiet hours wait here too,
+//! and the ones that come due together are merged into one digest.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use super::{Backend, Message, Notifier};
+use crate::config;
+
+/// How long to wait before the first retry. Doubles with every failure.
+const FIRST_RETRY: u64 = 10;
+
+/// The longest wait between retries.
+const MAX_RETRY: u64 = 3600;
+
+/// How often the sender looks at the outbox when nothing wakes it up.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    /// The notifier to deliver to.
+    notifier: String,
+    message: Message,
+
+    /// Unix time the message was queued.
+    created_at: u64,
+    attempts: u32,
+
+    /// Unix time of the next delivery attempt.
+    next_attempt: u64,
+    last_error: Option<String>,
+
+    /// Set while the message is held for a digest.
+    #[serde(default)]
+    held: Option<Held>,
+}
+
+/// Held messages for the same notifier with the same `Held` are merged when they come due.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Held {
+    /// The heading of the digest, like `{{count}} events during quiet hours`.
+    heading: String,
+    window: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    delivered: u64,
+    failed_attempts: u64,
+    dropped: u64,
+}
+
+pub struct Outbox {
+    dir: std::path::PathBuf,
+    max_age: u64,
+    metrics_file: Option<std::path::PathBuf>,
+
+    notifiers: Mutex<HashMap<String, Backend>>,
+    metrics: Mutex<BTreeMap<String, Counters>>,
+
+    /// Wakes the sender when something is queued.
+    wake: tokio::sync::Notify,
+
+    /// Keeps ids unique when messages are queued in the same nanosecond.
+    sequence: std::sync::atomic::AtomicU64,
+}
+
+impl Outbox {
+    pub fn new(outbox: &config::OutboxConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(outbox.dir.join("failed"))?;
+        Ok(Self {
+            dir: outbox.dir.clone(),
+            max_age: outbox.max_age,
+            metrics_file: outbox.metrics_file.clone(),
+            notifiers: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(BTreeMap::new()),
+            wake: tokio::sync::Notify::new(),
+            sequence: std::sync::atomic::AtomicU64::new(0),
+        })
+    }
+
+    /// Make a notifier available to the sender under its name.
+    pub fn register(&self, backend: &Backend) {
+        self.notifiers
+            .lock()
+            .unwrap()
+            .insert(backend.name.clone(), backend.clone());
+    }
+
+    /// Write the message to disk, to be delivered by the sender.
+    /// If even that fails, it is sent right away instead.
+    pub async fn push(&self, name: &str, notifier: &Arc<dyn Notifier>, message: Message) {
+        self.queue(name, notifier, message, unix_now(), None).await;
+    }
+
+    /// Like `push`, but the message waits until the Unix time `until`,
+    /// and is merged with the other messages held for the same digest that are due by then.
+    pub async fn hold(
+        &self,
+        backend: &Backend,
+        message: Message,
+        until: u64,
+        heading: &str,
+        window: u64,
+    ) {
+        let held = Held {
+            heading: heading.to_string(),
+            window,
+        };
+        self.queue(&backend.name, &backend.notifier, message, until, Some(held))
+            .await;
+    }
+
+    async fn queue(
+        &self,
+        name: &str,
+        notifier: &Arc<dyn Notifier>,
+        message: Message,
+        next_attempt: u64,
+        held: Option<Held>,
+    ) {
+        let now = unix_now();
+        let sequence = self
+            .sequence
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // Names sort in the order the messages were queued.
+        let id = format!(
+            "{:020}-{:06}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            sequence % 1_000_000
+        );
+        let entry = Entry {
+            notifier: name.to_string(),
+            message,
+            created_at: now,
+            attempts: 0,
+            next_attempt,
+            last_error: None,
+            held,
+        };
+
+        if let Err(why) = self.save(&id, &entry).await {
+            tracing::error!(
+                "Failed to queue message for {}, sending it directly: {}",
+                name,
+                why
+            );
+            if let Err(why) = notifier.send(&entry.message).await {
+                tracing::error!("Failed to send message via {}: {}", name, why);
+            }
+            return;
+        }
+        self.wake.notify_one();
+    }
+
+    /// Deliver queued messages until the watchdog exits.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(why) = self.deliver().await {
+                tracing::error!("Failed to read the outbox: {}", why);
+            }
+            if let Err(why) = self.write_metrics().await {
+                tracing::error!("Failed to write outbox metrics: {}", why);
+            }
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// One pass over the outbox.
+    /// Messages for the same notifier go out in order,
+    /// so once one of them has to wait, the later ones wait too.
+    async fn deliver(&self) -> std::io::Result<()> {
+        let mut ids = Vec::new();
+        let mut files = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_suffix(".json") {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+
+        let mut entries = Vec::new();
+        for id in ids {
+            let path = self.path(&id);
+            let entry: Entry = match tokio::fs::read(&path).await {
+                Ok(content) => match serde_json::from_slice(&content) {
+                    Ok(entry) => entry,
+                    Err(why) => {
+                        tracing::error!(
+                            "Outbox entry {} is corrupt, setting it aside: {}",
+                            id,
+                            why
+                        );
+                        tokio::fs::rename(&path, self.failed_path(&id)).await?;
+                        continue;
+                    }
+                },
+                Err(why) => {
+                    tracing::error!("Failed to read outbox entry {}: {}", id, why);
+                    continue;
+                }
+            };
+            entries.push((id, entry));
+        }
+        let entries = self.merge_held(entries, unix_now()).await?;
+
+        let mut waiting: HashSet<String> = HashSet::new();
+        for (id, mut entry) in entries {
+            let path = self.path(&id);
+            if waiting.contains(&entry.notifier) {
+                continue;
+            }
+            let now = unix_now();
+            if entry.next_attempt > now {
+                // Held messages wait for their own time, without holding up the rest,
+                // like critical messages during quiet hours.
+                if entry.held.is_none() {
+                    waiting.insert(entry.notifier.clone());
+                }
+                continue;
+            }
+
+            let notifier = self
+                .notifiers
+                .lock()
+                .unwrap()
+                .get(&entry.notifier)
+                .map(|backend| backend.notifier.clone());
+            // Errors that can't go away on their own fail the message right away.
+            let result = match notifier {
+                Some(notifier) => notifier
+                    .send(&entry.message)
+                    .await
+                    .map_err(|why| (why.to_string(), why.is_permanent())),
+                None => Err((format!("no notifier named {}", entry.notifier), false)),
+            };
+
+            match result {
+                Ok(()) => {
+                    if entry.attempts > 0 {
+                        tracing::info!(
+                            "Delivered queued message {} via {} after {} failed attempts",
+                            id,
+                            entry.notifier,
+                            entry.attempts
+                        );
+                    } else {
+                        tracing::debug!("Delivered message {} via {}", id, entry.notifier);
+                    }
+                    self.count(&entry.notifier, |counters| counters.delivered += 1);
+                    tokio::fs::remove_file(&path).await?;
+                }
+                Err((why, permanent)) => {
+                    self.count(&entry.notifier, |counters| counters.failed_attempts += 1);
+                    entry.attempts += 1;
+                    entry.last_error = Some(why.clone());
+
+                    if permanent {
+                        tracing::error!(
+                            "Cannot deliver message {} via {}, setting it aside: {}",
+                            id,
+                            entry.notifier,
+                            why
+                        );
+                        self.count(&entry.notifier, |counters| counters.dropped += 1);
+                        self.save(&id, &entry).await?;
+                        tokio::fs::rename(&path, self.failed_path(&id)).await?;
+                        continue;
+                    }
+
+                    // From now on it is retried in order, like any other message.
+                    entry.held = None;
+                    waiting.insert(entry.notifier.clone());
+
+                    if now.saturating_sub(entry.created_at) >= self.max_age {
+                        tracing::error!(
+                            "Giving up on message {} via {} after {} attempts: {}",
+                            id,
+                            entry.notifier,
+                            entry.attempts,
+                            why
+                        );
+                        self.count(&entry.notifier, |counters| counters.dropped += 1);
+                        self.save(&id, &entry).await?;
+                        tokio::fs::rename(&path, self.failed_path(&id)).await?;
+                        continue;
+                    }
+
+                    let delay = FIRST_RETRY
+                        .saturating_mul(1 << (entry.attempts - 1).min(16))
+                        .min(MAX_RETRY);
+                    entry.next_attempt = now + delay;
+                    tracing::warn!(
+                        "Failed to send message {} via {} (attempt {}), retrying in {}s: {}",
+                        id,
+                        entry.notifier,
+                        entry.attempts,
+                        delay,
+                        why
+                    );
+                    self.save(&id, &entry).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge the held messages for each notifier that are due into one digest,
+    /// which takes the place of the first of them.
+    async fn merge_held(
+        &self,
+        entries: Vec<(String, Entry)>,
+        now: u64,
+    ) -> std::io::Result<Vec<(String, Entry)>> {
+        let is_due = |entry: &Entry| entry.held.is_some() && entry.next_attempt <= now;
+
+        let mut entries: Vec<Option<(String, Entry)>> = entries.into_iter().map(Some).collect();
+        let mut merged = Vec::new();
+        for index in 0..entries.len() {
+            let Some((id, mut entry)) = entries[index].take() else {
+                continue;
+            };
+            let backend = self.notifiers.lock().unwrap().get(&entry.notifier).cloned();
+            if let (Some(held), Some(backend)) = (entry.held.clone(), backend)
+                && is_due(&entry)
+            {
+                let mut messages = vec![entry.message.clone()];
+                let mut others = Vec::new();
+                for later in entries[index + 1..].iter_mut() {
+                    if let Some((_, other)) = later
+                        && other.notifier == entry.notifier
+                        && other.held.as_ref() == Some(&held)
+                        && is_due(other)
+                    {
+                        let (other_id, other) = later.take().expect("checked above");
+                        messages.push(other.message);
+                        others.push(other_id);
+                    }
+                }
+
+                if !others.is_empty() {
+                    tracing::info!(
+                        "Sending {} held messages via {} as one digest",
+                        messages.len(),
+                        entry.notifier
+                    );
+                    entry.message =
+                        backend
+                            .templates
+                            .render_digest(&held.heading, held.window, &messages);
+                    self.save(&id, &entry).await?;
+                    for other_id in others {
+                        tokio::fs::remove_file(self.path(&other_id)).await?;
+                    }
+                }
+            }
+            merged.push((id, entry));
+        }
+        Ok(merged)
+    }
+
+    fn count(&self, notifier: &str, update: impl FnOnce(&mut Counters)) {
+        update(
+            self.metrics
+                .lock()
+                .unwrap()
+                .entry(notifier.to_string())
+                .or_default(),
+        );
+    }
+
+    /// Write the counters in the Prometheus text format,
+    /// for node_exporter's textfile collector.
+    async fn write_metrics(&self) -> std::io::Result<()> {
+        let Some(metrics_file) = &self.metrics_file else {
+            return Ok(());
+        };
+
+        let mut pending: BTreeMap<String, u64> = BTreeMap::new();
+        let mut files = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            if !file.file_name().to_string_lossy().ends_with(".json") {
+                continue;
+            }
+            if let Ok(content) = tokio::fs::read(file.path()).await
+                && let Ok(entry) = serde_json::from_slice::<Entry>(&content)
+            {
+                *pending.entry(entry.notifier).or_default() += 1;
+            }
+        }
+
+        let (mut delivered, mut failed_attempts, mut dropped) =
+            (BTreeMap::new(), BTreeMap::new(), BTreeMap::new());
+        for (notifier, counters) in self.metrics.lock().unwrap().iter() {
+            delivered.insert(notifier.clone(), counters.delivered);
+            failed_attempts.insert(notifier.clone(), counters.failed_attempts);
+            dropped.insert(notifier.clone(), counters.dropped);
+        }
+
+        let mut text = String::new();
+        metric(
+            &mut text,
+            "soft_watchdog_notifications_delivered_total",
+            "counter",
+            "Messages delivered since the watchdog started.",
+            &delivered,
+        );
+        metric(
+            &mut text,
+            "soft_watchdog_notifications_failed_attempts_total",
+            "counter",
+            "Delivery attempts that failed since the watchdog started.",
+            &failed_attempts,
+        );
+        metric(
+            &mut text,
+            "soft_watchdog_notifications_dropped_total",
+            "counter",
+            "Messages given up on since the watchdog started.",
+            &dropped,
+        );
+        metric(
+            &mut text,
+            "soft_watchdog_outbox_pending",
+            "gauge",
+            "Messages waiting in the outbox.",
+            &pending,
+        );
+
+        write_atomically(metrics_file, text.as_bytes()).await
+    }
+
+    async fn save(&self, id: &str, entry: &Entry) -> std::io::Result<()> {
+        let content = serde_json::to_vec_pretty(entry).expect("outbox entry is serializable");
+        write_atomically(&self.path(id), &content).await
+    }
+
+    fn path(&self, id: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn failed_path(&self, id: &str) -> std::path::PathBuf {
+        self.dir.join("failed").join(format!("{id}.json"))
+    }
+}
+
+fn metric(text: &mut String, name: &str, kind: &str, help: &str, values: &BTreeMap<String, u64>) {
+    *text += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+    for (notifier, value) in values {
+        *text += &format!("{name}{{notifier=\"{notifier}\"}} {value}\n");
+    }
+}
+
+/// Write to a temporary file first, so a crash never leaves half a file behind.
+async fn write_atomically(path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::{Recorder, backend};
+
+    fn message(text: &str) -> Message {
+        crate::notify::tests::message(config::EventKind::GracePeriod, text)
+    }
+
+    fn outbox(name: &str) -> (Outbox, Backend, Arc<Recorder>) {
+        let dir = std::env::temp_dir().join(format!(
+            "soft-watchdog-outbox-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let outbox = Outbox::new(&config::OutboxConfig {
+            dir,
+            max_age: 3600,
+            metrics_file: None,
+        })
+        .unwrap();
+        let (backend, recorder) = backend("test");
+        outbox.register(&backend);
+        (outbox, backend, recorder)
+    }
+
+    fn pending(outbox: &Outbox) -> usize {
+        count_json(&outbox.dir)
+    }
+
+    fn count_json(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|file| {
+                file.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".json")
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn held_messages_that_come_due_together_are_merged() {
+        let (outbox, backend, recorder) = outbox("merge");
+        let heading = crate::messages::QUIET_HOURS_HEADING;
+        let now = unix_now();
+        outbox.hold(&backend, message("one"), now, heading, 0).await;
+        outbox.hold(&backend, message("two"), now, heading, 0).await;
+        outbox
+            .hold(&backend, message("later"), now + 3600, heading, 0)
+            .await;
+
+        outbox.deliver().await.unwrap();
+
+        let sent = recorder.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject, "2 events during quiet hours");
+        assert!(sent[0].text.contains("VMID 100 (web): one"));
+        assert!(sent[0].text.contains("VMID 100 (web): two"));
+        assert_eq!(pending(&outbox), 1);
+    }
+
+    #[tokio::test]
+    async fn held_messages_do_not_hold_up_the_rest() {
+        let (outbox, backend, recorder) = outbox("bypass");
+        let now = unix_now();
+        outbox
+            .hold(&backend, message("held"), now + 3600, "{{count}}", 0)
+            .await;
+        outbox
+            .push(&backend.name, &backend.notifier, message("critical"))
+            .await;
+
+        outbox.deliver().await.unwrap();
+
+        let sent = recorder.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].text, "critical");
+        assert_eq!(pending(&outbox), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_that_cannot_be_delivered_are_set_aside() {
+        let (outbox, backend, recorder) = outbox("permanent");
+        *recorder.reject.lock().unwrap() = Some(reqwest::StatusCode::BAD_REQUEST);
+        outbox
+            .push(&backend.name, &backend.notifier, message("too long"))
+            .await;
+        outbox
+            .push(&backend.name, &backend.notifier, message("next"))
+            .await;
+
+        outbox.deliver().await.unwrap();
+
+        let sent = recorder.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].text, "next");
+        assert_eq!(pending(&outbox), 0);
+        assert_eq!(count_json(&outbox.dir.join("failed")), 1);
+    }
+
+    #[tokio::test]
+    async fn temporary_failures_hold_up_later_messages() {
+        let (outbox, backend, recorder) = outbox("temporary");
+        *recorder.reject.lock().unwrap() = Some(reqwest::StatusCode::TOO_MANY_REQUESTS);
+        outbox
+            .push(&backend.name, &backend.notifier, message("first"))
+            .await;
+        outbox
+            .push(&backend.name, &backend.notifier, message("second"))
+            .await;
+
+        outbox.deliver().await.unwrap();
+
+        assert!(recorder.sent.lock().unwrap().is_empty());
+        assert_eq!(pending(&outbox), 2);
+        assert_eq!(count_json(&outbox.dir.join("failed")), 0);
+    }
+}
@@ -0,0 +1,61 @@
+use super::{Alert, Error, Http, Message, Notifier, check_status};
+
+/// PagerDuty rejects longer summaries.
+const MAX_SUMMARY_CHARS: usize = 1024;
+
+/// Keeps one PagerDuty incident per guest, through the Events API v2.
+pub struct PagerDuty {
+    http: Http,
+    url: String,
+    routing_key: String,
+}
+
+impl PagerDuty {
+    pub fn new(http: Http, url: String, routing_key: String) -> Self {
+        Self {
+            http,
+            url,
+            routing_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for PagerDuty {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let body = match Alert::of(message) {
+            None => return Ok(()),
+            Some(Alert::Trigger(severity)) => serde_json::json!({
+                "routing_key": self.routing_key,
+                "event_action": "trigger",
+                "dedup_key": message.dedup_key(),
+                "payload": {
+                    "summary": message.full_text.chars().take(MAX_SUMMARY_CHARS).collect::<String>(),
+                    "source": if message.node.is_empty() { "proxmox-soft-watchdog" } else { &message.node },
+                    "severity": severity,
+                    "component": message.friendly_name,
+                    "custom_details": {
+                        "vmid": message.vmid,
+                        "friendly_name": message.friendly_name,
+                        "node": message.node,
+                        "kind": message.kind,
+                        "text": message.text,
+                    },
+                },
+            }),
+            Some(Alert::Resolve) => serde_json::json!({
+                "routing_key": self.routing_key,
+                "event_action": "resolve",
+                "dedup_key": message.dedup_key(),
+            }),
+        };
+
+        let res = self.http.client.post(&self.url).json(&body).send().await?;
+        check_status(res).await?;
+        Ok(())
+    }
+
+    fn batches(&self) -> bool {
+        false
+    }
+}
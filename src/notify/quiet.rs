@@ -0,0 +1,185 @@
+//! Quiet hours for one notifier:
+//! only critical messages go out right away,
+//! and the rest wait for one digest when the quiet hours end,
+//! in the outbox if there is one, so that a restart doesn't lose them.
+
+use std::sync::{Arc, Mutex};
+
+use super::{Backend, Error, Message, deliver, outbox};
+use crate::{config, messages};
+
+pub struct QuietHours {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+
+    /// Messages waiting for the end of the quiet hours, without an outbox.
+    held: Mutex<Vec<Message>>,
+}
+
+impl QuietHours {
+    pub fn new(quiet_hours: &config::QuietHours) -> Result<Self, Error> {
+        let parse = |time: &str| {
+            chrono::NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|why| Error::Config(format!("invalid quiet hours time {time:?}: {why}")))
+        };
+        Ok(Self {
+            start: parse(&quiet_hours.start)?,
+            end: parse(&quiet_hours.end)?,
+            held: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Should this message wait until the quiet hours are over?
+    pub fn holds(&self, message: &Message) -> bool {
+        message.severity != config::Severity::Critical && self.is_quiet(chrono::Local::now().time())
+    }
+
+    fn is_quiet(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // Like 22:00 to 07:00, over midnight.
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// How long from `now` until the next end of the quiet hours.
+    fn until_end(&self, now: chrono::NaiveDateTime) -> std::time::Duration {
+        let mut end = now.date().and_time(self.end);
+        if end <= now {
+            end += chrono::Duration::days(1);
+        }
+        (end - now).to_std().unwrap_or_default()
+    }
+
+    /// Keep the message for the digest at the end of the quiet hours.
+    pub async fn hold(
+        self: &Arc<Self>,
+        backend: &Backend,
+        outbox: Option<&outbox::Outbox>,
+        message: Message,
+    ) {
+        let wait = self.until_end(chrono::Local::now().naive_local());
+
+        if let Some(outbox) = outbox {
+            let until = outbox::unix_now() + wait.as_secs();
+            outbox
+                .hold(backend, message, until, messages::QUIET_HOURS_HEADING, 0)
+                .await;
+            return;
+        }
+
+        let mut held = self.held.lock().unwrap();
+        held.push(message);
+        if held.len() > 1 {
+            return;
+        }
+
+        let quiet_hours = self.clone();
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            quiet_hours.flush(&backend).await;
+        });
+    }
+
+    async fn flush(&self, backend: &Backend) {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        let message = match held.len() {
+            0 => return,
+            1 => held.into_iter().next().expect("checked the length"),
+            count => {
+                tracing::info!(
+                    "Quiet hours are over, sending {} held messages via {}",
+                    count,
+                    backend.name
+                );
+                backend
+                    .templates
+                    .render_digest(messages::QUIET_HOURS_HEADING, 0, &held)
+            }
+        };
+        deliver(None, backend, message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_hours(start: &str, end: &str) -> QuietHours {
+        QuietHours::new(&config::QuietHours {
+            start: start.to_string(),
+            end: end.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn time(time: &str) -> chrono::NaiveTime {
+        chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn quiet_within_the_same_day() {
+        let quiet_hours = quiet_hours("13:00", "14:30");
+        assert!(!quiet_hours.is_quiet(time("12:59")));
+        assert!(quiet_hours.is_quiet(time("13:00")));
+        assert!(quiet_hours.is_quiet(time("14:29")));
+        assert!(!quiet_hours.is_quiet(time("14:30")));
+        assert!(!quiet_hours.is_quiet(time("02:00")));
+    }
+
+    #[test]
+    fn quiet_over_midnight() {
+        let quiet_hours = quiet_hours("22:00", "07:00");
+        assert!(!quiet_hours.is_quiet(time("21:59")));
+        assert!(quiet_hours.is_quiet(time("22:00")));
+        assert!(quiet_hours.is_quiet(time("23:59")));
+        assert!(quiet_hours.is_quiet(time("00:00")));
+        assert!(quiet_hours.is_quiet(time("06:59")));
+        assert!(!quiet_hours.is_quiet(time("07:00")));
+        assert!(!quiet_hours.is_quiet(time("12:00")));
+    }
+
+    #[test]
+    fn never_quiet_when_start_and_end_are_the_same() {
+        let quiet_hours = quiet_hours("08:00", "08:00");
+        assert!(!quiet_hours.is_quiet(time("08:00")));
+        assert!(!quiet_hours.is_quiet(time("20:00")));
+    }
+
+    #[test]
+    fn held_messages_wait_for_the_next_end() {
+        let quiet_hours = quiet_hours("22:00", "07:00");
+        let at = |time: &str| {
+            chrono::NaiveDate::from_ymd_opt(2026, 3, 1)
+                .unwrap()
+                .and_time(self::time(time))
+        };
+        let hours = |hours: u64| std::time::Duration::from_secs(hours * 3600);
+        assert_eq!(quiet_hours.until_end(at("23:00")), hours(8));
+        assert_eq!(quiet_hours.until_end(at("01:00")), hours(6));
+    }
+
+    #[test]
+    fn critical_messages_are_never_held() {
+        // Quiet all day but for a minute.
+        let quiet_hours = quiet_hours("00:00", "23:59");
+        let message =
+            crate::notify::tests::message(config::EventKind::Reset, "Resetting machine now");
+        assert!(!quiet_hours.holds(&message));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for (start, end) in [("22", "07:00"), ("22:00", "7am"), ("25:00", "07:00")] {
+            assert!(
+                QuietHours::new(&config::QuietHours {
+                    start: start.to_string(),
+                    end: end.to_string(),
+                })
+                .is_err()
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! A token bucket for the messages of one guest,
+//! so that a guest flapping between OK and the grace period can't flood the chat.
+
+use crate::config;
+
+pub struct TokenBucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: std::time::Instant,
+
+    /// Messages dropped since the last one that got through.
+    pub suppressed: u32,
+}
+
+impl TokenBucket {
+    pub fn new(rate_limit: &config::RateLimit) -> Self {
+        Self {
+            burst: rate_limit.burst as f64,
+            per_second: rate_limit.per_hour as f64 / 3600.0,
+            tokens: rate_limit.burst as f64,
+            refilled_at: std::time::Instant::now(),
+            suppressed: 0,
+        }
+    }
+
+    /// Use up a token if there is one.
+    pub fn take(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(burst: u32, per_hour: u32) -> TokenBucket {
+        TokenBucket::new(&config::RateLimit { burst, per_hour })
+    }
+
+    /// Pretend the last refill was this long ago.
+    fn wait(bucket: &mut TokenBucket, secs: u64) {
+        bucket.refilled_at -= std::time::Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn allows_a_burst_then_stops() {
+        let mut bucket = bucket(3, 0);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        // One message a minute.
+        let mut bucket = bucket(1, 60);
+        assert!(bucket.take());
+        assert!(!bucket.take());
+
+        wait(&mut bucket, 30);
+        assert!(!bucket.take());
+        wait(&mut bucket, 31);
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn never_holds_more_than_the_burst() {
+        let mut bucket = bucket(2, 3600);
+        wait(&mut bucket, 24 * 3600);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+}
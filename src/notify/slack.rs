@@ -0,0 +1,28 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+/// Posts to a Slack incoming webhook.
+pub struct Slack {
+    http: Http,
+    webhook_url: String,
+}
+
+impl Slack {
+    pub fn new(http: Http, webhook_url: String) -> Self {
+        Self { http, webhook_url }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Slack {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let res = self
+            .http
+            .client
+            .post(&self.webhook_url)
+            .json(&serde_json::json!({ "text": message.full_text_with_attachment_path() }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+}
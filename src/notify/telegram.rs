@@ -0,0 +1,94 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+pub struct Telegram {
+    http: Http,
+    api_url: String,
+    bot_token: String,
+    chat_id: String,
+}
+
+impl Telegram {
+    pub fn new(http: Http, api_url: String, bot_token: String, chat_id: String) -> Self {
+        Self {
+            http,
+            api_url,
+            bot_token,
+            chat_id,
+        }
+    }
+
+    fn method_url(&self, method: &str) -> String {
+        format!("{}/bot{}/{}", self.api_url, self.bot_token, method)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Sent {
+    result: SentMessage,
+}
+
+#[derive(serde::Deserialize)]
+struct SentMessage {
+    message_id: i64,
+}
+
+#[async_trait::async_trait]
+impl Notifier for Telegram {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let Some(path) = &message.attachment else {
+            self.send_editable(message).await?;
+            return Ok(());
+        };
+
+        let photo = tokio::fs::read(path).await?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let form = reqwest::multipart::Form::new()
+            .text("chat_id", self.chat_id.clone())
+            .text("caption", message.full_text.clone())
+            .part(
+                "photo",
+                reqwest::multipart::Part::bytes(photo).file_name(file_name),
+            );
+
+        let res = self
+            .http
+            .upload
+            .post(self.method_url("sendPhoto"))
+            .multipart(form)
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+
+    async fn send_editable(&self, message: &Message) -> Result<Option<String>, Error> {
+        let res = self
+            .http
+            .client
+            .post(self.method_url("sendMessage"))
+            .json(&serde_json::json!({"chat_id": self.chat_id, "text": message.full_text}))
+            .send()
+            .await?;
+        let sent: Sent = check_status(res).await?.json().await?;
+        Ok(Some(sent.result.message_id.to_string()))
+    }
+
+    async fn edit(&self, id: &str, message: &Message) -> Result<(), Error> {
+        let res = self
+            .http
+            .client
+            .post(self.method_url("editMessageText"))
+            .json(&serde_json::json!({
+                "chat_id": self.chat_id,
+                "message_id": id,
+                "text": message.full_text,
+            }))
+            .send()
+            .await?;
+        check_status(res).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use super::{Error, Http, Message, Notifier, check_status};
+
+/// POSTs every message as JSON to a URL.
+pub struct Webhook {
+    http: Http,
+    url: String,
+    headers: std::collections::BTreeMap<String, String>,
+}
+
+impl Webhook {
+    pub fn new(
+        http: Http,
+        url: String,
+        headers: std::collections::BTreeMap<String, String>,
+    ) -> Self {
+        Self { http, url, headers }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for Webhook {
+    async fn send(&self, message: &Message) -> Result<(), Error> {
+        let mut request = self.http.client.post(&self.url).json(&serde_json::json!({
+            "vmid": message.vmid,
+            "friendly_name": message.friendly_name,
+            "kind": message.kind,
+            "severity": message.severity,
+            "subject": message.subject,
+            "text": message.body(),
+            "attachment": message.attachment,
+        }));
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+
+        check_status(request.send().await?).await?;
+        Ok(())
+    }
+}
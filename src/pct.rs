@@ -0,0 +1,134 @@
+//! LXC containers have no guest agent,
+//! so we exchange the heartbeat files by running `pct exec` on the host.
+//! This only works for containers on the node the watchdog runs on.
+
+use tokio::io::AsyncWriteExt;
+
+use crate::api::Error;
+
+/// How long a single `pct exec` for the heartbeat files
+/// may take before we consider the container hung.
+const EXEC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Run a command in the container, and return its stdout.
+#[tracing::instrument(skip(stdin), level = "debug")]
+pub async fn exec(
+    vmid: &str,
+    command: &[&str],
+    stdin: Option<&[u8]>,
+    timeout: std::time::Duration,
+) -> Result<Vec<u8>, Error> {
+    let mut pct = tokio::process::Command::new("pct");
+    pct.arg("exec").arg(vmid).arg("--").args(command);
+    run(pct, stdin, timeout).await
+}
+
+/// Run a host command, feeding it `stdin`, and kill it once `timeout` has passed.
+async fn run(
+    mut command: tokio::process::Command,
+    stdin: Option<&[u8]>,
+    timeout: std::time::Duration,
+) -> Result<Vec<u8>, Error> {
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(Error::Local)?;
+
+    // Dropping stdin closes it, so the command sees EOF.
+    let mut child_stdin = child.stdin.take().expect("stdin is piped");
+    let run = async move {
+        if let Some(stdin) = stdin {
+            child_stdin.write_all(stdin).await?;
+        }
+        drop(child_stdin);
+        child.wait_with_output().await
+    };
+
+    let output = match tokio::time::timeout(timeout, run).await {
+        Ok(output) => output.map_err(Error::Local)?,
+        Err(_) => {
+            return Err(Error::AgentNotRunning(format!(
+                "pct exec did not finish in {} seconds",
+                timeout.as_secs()
+            )));
+        }
+    };
+
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if stderr.contains("not running") {
+        Err(Error::AgentNotRunning(stderr))
+    } else if stderr.contains("does not exist") {
+        Err(Error::NotFound(stderr))
+    } else {
+        Err(Error::CommandFailed(format!(
+            "{}: {}",
+            output.status, stderr
+        )))
+    }
+}
+
+/// The name of the node the watchdog runs on, which Proxmox takes from the host name.
+pub fn local_node() -> Option<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    let node = hostname.trim().split('.').next()?;
+    (!node.is_empty()).then(|| node.to_string())
+}
+
+pub async fn ping(vmid: &str) -> Result<(), Error> {
+    exec(vmid, &["true"], None, EXEC_TIMEOUT).await?;
+    Ok(())
+}
+
+pub async fn write_file(vmid: &str, path: &str, content: &[u8]) -> Result<(), Error> {
+    exec(
+        vmid,
+        &["sh", "-c", "cat > \"$1\"", "sh", path],
+        Some(content),
+        EXEC_TIMEOUT,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn read_file(vmid: &str, path: &str) -> Result<String, Error> {
+    let content = exec(vmid, &["cat", path], None, EXEC_TIMEOUT).await?;
+    String::from_utf8(content).map_err(|why| Error::Malformed(why.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_given_timeout() {
+        let started = std::time::Instant::now();
+        let result = run(sh("sleep 5"), None, std::time::Duration::from_millis(200)).await;
+        assert!(matches!(result, Err(Error::AgentNotRunning(_))));
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_the_timeout_allows() {
+        let output = run(
+            sh("sleep 1; cat"),
+            Some(b"done"),
+            std::time::Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, b"done");
+    }
+}
@@ -0,0 +1,207 @@
+//! Keeps track of the running monitoring tasks,
+//! so that they can be started and stopped while the watchdog is running.
+
+use std::collections::HashMap;
+
+use tracing::Instrument;
+
+use crate::{api, config, messages, monitoring, notify};
+
+struct RunningMonitor {
+    config: config::VmConfig,
+
+    /// Dropping or sending on this stops the monitoring loop after its current tick.
+    shutdown: tokio::sync::oneshot::Sender<()>,
+    commands: tokio::sync::mpsc::Sender<monitoring::Command>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+pub struct Registry {
+    api: api::Api,
+    notifiers: notify::Notifiers,
+    monitors: HashMap<String, RunningMonitor>,
+}
+
+impl Registry {
+    pub fn new(api: api::Api, notifiers: notify::Notifiers) -> Self {
+        Self {
+            api,
+            notifiers,
+            monitors: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self, vmid: &str) -> Option<&config::VmConfig> {
+        self.monitors.get(vmid).map(|monitor| &monitor.config)
+    }
+
+    /// Find a monitored guest by its VMID or friendly name.
+    pub fn find(&self, name: &str) -> Option<&config::VmConfig> {
+        self.monitors
+            .values()
+            .map(|monitor| &monitor.config)
+            .find(|config| config.vmid == name || config.friendly_name == name)
+    }
+
+    pub fn configs(&self) -> impl Iterator<Item = &config::VmConfig> {
+        self.monitors.values().map(|monitor| &monitor.config)
+    }
+
+    /// Where to send commands for this guest's monitor.
+    pub fn commands(&self, vmid: &str) -> Option<tokio::sync::mpsc::Sender<monitoring::Command>> {
+        self.monitors
+            .get(vmid)
+            .map(|monitor| monitor.commands.clone())
+    }
+
+    /// Start monitoring a guest, unless it is already being monitored.
+    pub fn start(&mut self, vm_config: config::VmConfig) {
+        if self.monitors.contains_key(&vm_config.vmid) {
+            tracing::warn!(
+                "VMID {} is already being monitored, not starting it again",
+                vm_config.vmid
+            );
+            return;
+        }
+
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+        let (commands, commands_rx) = tokio::sync::mpsc::channel(16);
+        let task = tokio::spawn(run_monitor(
+            self.api.clone(),
+            vm_config.clone(),
+            self.notifiers.for_vm(&vm_config),
+            shutdown_rx,
+            commands_rx,
+        ));
+        self.monitors.insert(
+            vm_config.vmid.clone(),
+            RunningMonitor {
+                config: vm_config,
+                shutdown,
+                commands,
+                task,
+            },
+        );
+    }
+
+    /// Give a running monitor new settings, without starting it over.
+    /// If it can't take them right now, the old ones are kept,
+    /// so that the caller sees the difference and tries again later.
+    pub fn reconfigure(&mut self, vm_config: config::VmConfig) {
+        let Some(monitor) = self.monitors.get_mut(&vm_config.vmid) else {
+            return;
+        };
+
+        let command = monitoring::Command::Reconfigure(Box::new(vm_config.clone()));
+        match monitor.commands.try_send(command) {
+            Ok(()) => monitor.config = vm_config,
+            Err(why) => tracing::warn!(
+                "VMID {} cannot take new settings right now: {}",
+                vm_config.vmid,
+                why
+            ),
+        }
+    }
+
+    /// Stop monitoring a guest.
+    /// The returned future waits for its loop to finish, which can take a while
+    /// if it is in the middle of a reset, so it is best awaited without the registry locked.
+    pub fn stop(&mut self, vmid: &str) -> Option<impl std::future::Future<Output = ()> + use<>> {
+        let monitor = self.monitors.remove(vmid)?;
+
+        let _ = monitor.shutdown.send(());
+        let vmid = vmid.to_string();
+        Some(async move {
+            if let Err(why) = monitor.task.await {
+                tracing::error!("VMID {} monitoring task failed: {}", vmid, why);
+            }
+        })
+    }
+}
+
+/// What the monitoring loop does next.
+enum Step {
+    Tick,
+    Command(monitoring::Command),
+}
+
+async fn run_monitor(
+    api: api::Api,
+    mut vm_config: config::VmConfig,
+    notifiers: notify::Notifiers,
+    mut shutdown: tokio::sync::oneshot::Receiver<()>,
+    mut commands: tokio::sync::mpsc::Receiver<monitoring::Command>,
+) {
+    let vmid = vm_config.vmid.clone();
+    let mut monitor =
+        monitoring::SingleMachineMonitoring::new(api.clone(), vm_config.clone(), notifiers.clone());
+    monitor
+        .say(
+            config::EventKind::Monitoring,
+            messages::MONITORING_STARTED,
+            &[],
+        )
+        .await;
+    let mut step = Step::Tick;
+    loop {
+        // Each step runs in its own task, so that a panic in it,
+        // like from something odd the guest wrote, starts the monitor over,
+        // instead of silently ending it.
+        let span = match &step {
+            Step::Tick => tracing::info_span!("tick", vmid = vmid),
+            Step::Command(command) => {
+                // Starting over after a panic uses the latest settings.
+                if let monitoring::Command::Reconfigure(new_config) = command {
+                    vm_config = (**new_config).clone();
+                }
+                tracing::info_span!("command", vmid = vmid)
+            }
+        };
+        let task = tokio::spawn(
+            async move {
+                match step {
+                    Step::Tick => monitor.tick().await,
+                    Step::Command(command) => monitor.handle_command(command).await,
+                }
+                monitor
+            }
+            .instrument(span),
+        );
+        monitor = match task.await {
+            Ok(monitor) => monitor,
+            Err(why) => {
+                tracing::error!(
+                    "VMID {} monitoring task failed, starting over: {}",
+                    vmid,
+                    why
+                );
+                let monitor = monitoring::SingleMachineMonitoring::new(
+                    api.clone(),
+                    vm_config.clone(),
+                    notifiers.clone(),
+                );
+                monitor
+                    .say(
+                        config::EventKind::Monitoring,
+                        messages::MONITORING_CRASHED,
+                        &[],
+                    )
+                    .await;
+                monitor
+            }
+        };
+
+        step = tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => Step::Tick,
+            _ = &mut shutdown => break,
+            Some(command) = commands.recv() => Step::Command(command),
+        };
+    }
+    monitor
+        .say(
+            config::EventKind::Monitoring,
+            messages::MONITORING_STOPPED,
+            &[],
+        )
+        .await;
+}
@@ -0,0 +1,368 @@
+//! Takes commands from a Telegram chat, so that alerts can be dealt with
+//! from a phone instead of editing the config and restarting the watchdog.
+
+use std::sync::Arc;
+
+use crate::{config, monitoring, registry};
+
+/// How long Telegram may hold a `getUpdates` request open when there is nothing new.
+const POLL_TIMEOUT_SECS: u64 = 50;
+
+/// Commands older than this are ignored, so that a `/reset` sent while the watchdog
+/// was down, or handled right before it restarted, isn't carried out when it comes back.
+const MAX_COMMAND_AGE_SECS: i64 = 60;
+
+/// How long we wait for a monitor to answer `/status`.
+/// It may be in the middle of a slow tick.
+const STATUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+const HELP: &str = "Commands:
+/status [vm] - show what the watchdog thinks of the machines
+/snooze <vm> <duration> - don't alert or reset for a while, e.g. 30m or 2h, at most 7d
+/ack <vm> - during a grace period, don't reset until the machine reports OK again
+/reset <vm> - reset the machine now
+/dryrun <vm> on|off - only report what would have been done
+
+<vm> is a VMID or friendly name.";
+
+pub async fn run(
+    bot: config::TelegramBotConfig,
+    registry: Arc<tokio::sync::Mutex<registry::Registry>>,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(POLL_TIMEOUT_SECS + 10))
+        .build()
+        .expect("cannot build Telegram client");
+    let method_url = |method: &str| format!("{}/bot{}/{}", bot.api_url, bot.bot_token, method);
+
+    let mut offset = 0;
+    loop {
+        let updates = match get_updates(&client, &method_url("getUpdates"), offset).await {
+            Ok(updates) => updates,
+            Err(why) => {
+                // The URL has the bot token in it.
+                tracing::error!("Failed to get Telegram updates: {}", why.without_url());
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        for update in updates {
+            offset = offset.max(update.update_id + 1);
+
+            let Some(message) = update.message else {
+                continue;
+            };
+            let Some(text) = message.text else {
+                continue;
+            };
+            if !bot.authorized_chat_ids.contains(&message.chat.id) {
+                tracing::warn!(
+                    "Ignoring Telegram command from unauthorized chat {}",
+                    message.chat.id
+                );
+                continue;
+            }
+
+            let age = chrono::Utc::now().timestamp() - message.date;
+            if age > MAX_COMMAND_AGE_SECS {
+                tracing::warn!(
+                    "Ignoring Telegram command from chat {} sent {}s ago: {}",
+                    message.chat.id,
+                    age,
+                    text
+                );
+                continue;
+            }
+
+            tracing::info!("Telegram command from chat {}: {}", message.chat.id, text);
+            let reply = handle(&text, &registry).await;
+
+            let res = client
+                .post(method_url("sendMessage"))
+                .json(&serde_json::json!({"chat_id": message.chat.id, "text": reply}))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            if let Err(why) = res {
+                tracing::error!("Failed to reply to Telegram command: {}", why.without_url());
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<TelegramMessage>,
+}
+
+#[derive(serde::Deserialize)]
+struct TelegramMessage {
+    chat: Chat,
+
+    /// Unix time the message was sent.
+    date: i64,
+    text: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(serde::Deserialize)]
+struct Updates {
+    result: Vec<Update>,
+}
+
+async fn get_updates(
+    client: &reqwest::Client,
+    url: &str,
+    offset: i64,
+) -> Result<Vec<Update>, reqwest::Error> {
+    let updates: Updates = client
+        .get(url)
+        .query(&[
+            ("offset", offset.to_string()),
+            ("timeout", POLL_TIMEOUT_SECS.to_string()),
+            ("allowed_updates", r#"["message"]"#.to_string()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(updates.result)
+}
+
+/// A command from the chat, before the guest it names is looked up.
+enum Request<'a> {
+    StatusAll,
+
+    /// `None` asks for the status of the guest.
+    Guest(&'a str, Option<monitoring::Command>),
+}
+
+/// Understand a command, or return the reply explaining what's wrong with it.
+fn parse(text: &str) -> Result<Request<'_>, String> {
+    let mut words = text.split_whitespace();
+    let Some(command) = words.next() else {
+        return Err(HELP.to_string());
+    };
+    // In group chats commands look like `/status@my_watchdog_bot`.
+    let command = command.split('@').next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+
+    let command = match (command, args.as_slice()) {
+        ("/status", []) => return Ok(Request::StatusAll),
+        ("/status", [_]) => None,
+        ("/snooze", [_, duration]) => match parse_duration(duration) {
+            Some(duration) => Some(monitoring::Command::Snooze(duration)),
+            None => {
+                return Err(format!(
+                    "Cannot understand duration {duration:?}, try 30m or 2h"
+                ));
+            }
+        },
+        ("/ack", [_]) => Some(monitoring::Command::Ack),
+        ("/reset", [_]) => Some(monitoring::Command::Reset),
+        ("/dryrun", [_, "on"]) => Some(monitoring::Command::DryRun(true)),
+        ("/dryrun", [_, "off"]) => Some(monitoring::Command::DryRun(false)),
+        _ => return Err(HELP.to_string()),
+    };
+    Ok(Request::Guest(args[0], command))
+}
+
+/// Run one command, and return the reply for the chat.
+async fn handle(text: &str, registry: &tokio::sync::Mutex<registry::Registry>) -> String {
+    let (name, command) = match parse(text) {
+        Ok(Request::StatusAll) => return status_all(registry).await,
+        Ok(Request::Guest(name, command)) => (name, command),
+        Err(reply) => return reply,
+    };
+
+    // Don't hold the registry while the monitor is busy,
+    // discovery needs it too.
+    let Some((vmid, commands)) = ({
+        let registry = registry.lock().await;
+        registry.find(name).and_then(|config| {
+            registry
+                .commands(&config.vmid)
+                .map(|commands| (config.vmid.clone(), commands))
+        })
+    }) else {
+        return format!("{name} is not being monitored");
+    };
+
+    match command {
+        None => status(&vmid, &commands).await,
+        Some(command) => match commands.send(command).await {
+            Ok(()) => format!("VMID {vmid}: command accepted"),
+            Err(_) => format!("VMID {vmid} is no longer being monitored"),
+        },
+    }
+}
+
+async fn status(vmid: &str, commands: &tokio::sync::mpsc::Sender<monitoring::Command>) -> String {
+    let (reply, reply_rx) = tokio::sync::oneshot::channel();
+    if commands
+        .send(monitoring::Command::Status(reply))
+        .await
+        .is_err()
+    {
+        return format!("VMID {vmid} is no longer being monitored");
+    }
+    match tokio::time::timeout(STATUS_TIMEOUT, reply_rx).await {
+        Ok(Ok(status)) => format!("VMID {vmid}: {status}"),
+        Ok(Err(_)) => format!("VMID {vmid} is no longer being monitored"),
+        Err(_) => format!("VMID {vmid} is busy and did not answer"),
+    }
+}
+
+async fn status_all(registry: &tokio::sync::Mutex<registry::Registry>) -> String {
+    let mut guests: Vec<_> = {
+        let registry = registry.lock().await;
+        registry
+            .configs()
+            .filter_map(|config| {
+                registry
+                    .commands(&config.vmid)
+                    .map(|commands| (config.vmid.clone(), config.friendly_name.clone(), commands))
+            })
+            .collect()
+    };
+    if guests.is_empty() {
+        return "No machines are being monitored".to_string();
+    }
+    guests.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut lines = Vec::new();
+    for (vmid, friendly_name, commands) in guests {
+        lines.push(format!(
+            "{} ({})",
+            status(&vmid, &commands).await,
+            friendly_name
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Parse durations like `90s`, `30m`, `2h` or `1h30m`. A bare number is minutes.
+fn parse_duration(text: &str) -> Option<std::time::Duration> {
+    if let Ok(minutes) = text.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(minutes.checked_mul(60)?));
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+    Some(std::time::Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Option<monitoring::Command> {
+        match parse(text) {
+            Ok(Request::Guest(name, command)) => {
+                assert_eq!(name, "web");
+                command
+            }
+            _ => panic!("{text:?} did not parse as a command for web"),
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        let secs = |secs| Some(std::time::Duration::from_secs(secs));
+        assert_eq!(parse_duration("90s"), secs(90));
+        assert_eq!(parse_duration("30m"), secs(30 * 60));
+        assert_eq!(parse_duration("2h"), secs(2 * 3600));
+        assert_eq!(parse_duration("1h30m"), secs(5400));
+        assert_eq!(parse_duration("7d"), secs(7 * 24 * 3600));
+        assert_eq!(parse_duration("15"), secs(15 * 60));
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        for text in ["", "0m", "m", "30x", "1h30", "-5m", "1.5h"] {
+            assert_eq!(parse_duration(text), None, "{text:?}");
+        }
+        assert_eq!(parse_duration("18446744073709551615"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+        assert_eq!(parse_duration("5124095576030431d"), None);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(parse("/status"), Ok(Request::StatusAll)));
+        assert!(matches!(
+            parse("/status@my_watchdog_bot"),
+            Ok(Request::StatusAll)
+        ));
+        assert!(command("/status web").is_none());
+        assert!(matches!(
+            command("/snooze web 2h"),
+            Some(monitoring::Command::Snooze(duration)) if duration.as_secs() == 7200
+        ));
+        assert!(matches!(
+            command("/ack web"),
+            Some(monitoring::Command::Ack)
+        ));
+        assert!(matches!(
+            command("/reset@my_watchdog_bot web"),
+            Some(monitoring::Command::Reset)
+        ));
+        assert!(matches!(
+            command("/dryrun web on"),
+            Some(monitoring::Command::DryRun(true))
+        ));
+        assert!(matches!(
+            command("/dryrun  web  off"),
+            Some(monitoring::Command::DryRun(false))
+        ));
+    }
+
+    #[test]
+    fn explains_bad_commands() {
+        for text in [
+            "",
+            "hello",
+            "/reset",
+            "/reset web now",
+            "/ack",
+            "/snooze web",
+            "/dryrun web maybe",
+            "/status web extra",
+        ] {
+            assert!(
+                matches!(parse(text), Err(reply) if reply == HELP),
+                "{text:?}"
+            );
+        }
+        assert!(matches!(
+            parse("/snooze web soon"),
+            Err(reply) if reply.starts_with("Cannot understand duration")
+        ));
+    }
+}
@@ -0,0 +1,647 @@
+//! Turns watchdog events into text, in the language and layout each notifier wants.
+//!
+//! The messages in the code are English Handlebars templates,
+//! and they double as the keys of the bundled translations,
+//! so a message without a translation still comes out in English.
+
+use std::collections::BTreeMap;
+
+use crate::{config, messages, notify};
+
+const EN_SUBJECT: &str = "VMID {{vmid}} ({{friendly_name}})";
+const RU_SUBJECT: &str = "ВМ {{vmid}} ({{friendly_name}})";
+const FULL_TEXT: &str = "{{subject}}: {{text}}";
+
+/// Russian translations of every message the watchdog sends.
+const RU: &[(&str, &str)] = &[
+    (messages::MONITORING_STARTED, "Мониторинг запущен!"),
+    (messages::MONITORING_STOPPED, "Мониторинг остановлен"),
+    (
+        messages::MONITORING_CRASHED,
+        "Мониторинг аварийно завершился и был запущен заново",
+    ),
+    (
+        messages::MIGRATED,
+        "Машина переехала с узла {{from}} на узел {{to}}",
+    ),
+    (
+        messages::CONTAINER_ON_OTHER_NODE,
+        "Контейнер находится на узле {{node}}, а watchdog работает на узле {{local_node}} и видит только его контейнеры, поэтому он не отслеживается, пока не вернётся",
+    ),
+    (
+        messages::POWERED_ON,
+        "Машина включена, запущен таймер перезагрузки",
+    ),
+    (
+        messages::SHUT_DOWN_AFTER_STEP,
+        "Машина выключилась после шага эскалации {{step}}, запускаем её снова",
+    ),
+    (
+        messages::START_FAILED,
+        "Не удалось запустить машину: {{error}}. Повторим в {{retry_time}}",
+    ),
+    (
+        messages::STEP_DID_NOT_SHUT_DOWN,
+        "Шаг эскалации {{step}} не выключил машину вовремя",
+    ),
+    (
+        messages::POWERED_OFF,
+        "Машина выключена, мониторинг остановлен",
+    ),
+    (
+        messages::SNOOZE_OVER,
+        "Пауза закончилась, мониторинг возобновлён",
+    ),
+    (
+        messages::RESET_TIMER_COMPLETED,
+        "Таймер перезагрузки истёк, мониторинг возобновлён",
+    ),
+    (
+        messages::RETRYING_ESCALATION,
+        "Повторяем эскалацию после неудачной перезагрузки",
+    ),
+    (
+        messages::PINGS_FAILED,
+        "Машина не ответила на 5 пингов {{channel}} подряд. Начат льготный период",
+    ),
+    (
+        messages::CURRENT_TIME_WRITE_FAILED,
+        "Watchdog не смог записать текущее время в файл гостя {{path}}. Начат льготный период",
+    ),
+    (
+        messages::NONCE_WRITE_FAILED,
+        "Watchdog не смог записать nonce в файл гостя {{path}}. Начат льготный период",
+    ),
+    (
+        messages::HEARTBEAT_REJECTED,
+        "Пульс {{heartbeat}} в {{path}} отклонён: {{error}}. {{t consequence}}",
+    ),
+    (
+        messages::HEARTBEAT_READ_FAILED,
+        "Watchdog не смог прочитать пульс {{heartbeat}} из файла гостя {{path}}. Возможно, файла нет? {{t consequence}}",
+    ),
+    (
+        messages::HEARTBEAT_PARSE_FAILED,
+        "Watchdog не смог разобрать пульс {{heartbeat}} в {{path}} ни как время Unix, ни как JSON. {{t consequence}}",
+    ),
+    (
+        messages::HEARTBEAT_REPORTS_PROBLEM,
+        "Машина сообщает о проблеме в пульсе {{heartbeat}}: {{reason}}. {{t consequence}}",
+    ),
+    (messages::GRACE_PERIOD_STARTED, "Начат льготный период"),
+    (
+        messages::OPTIONAL_HEARTBEAT_LAPSED,
+        "Этот пульс необязательный, поэтому льготный период не начат",
+    ),
+    (
+        messages::CURRENT_HEARTBEAT_TEXT,
+        "Сейчас в {{path}} записано: \n\n{{content}}",
+    ),
+    (
+        messages::TOO_FAR,
+        "Машина запросила перезагрузку на {{reset_time}} в пульсе {{heartbeat}}, это слишком далеко в будущем. Это нормально, если идёт ручное обслуживание.",
+    ),
+    (messages::MACHINE_OK, "Машина в порядке"),
+    (
+        messages::HEARTBEAT_NOT_UPDATED,
+        "Машина давно не обновляла пульс {{heartbeat}} в {{path}} (последнее обновление: {{reset_time}}). {{t consequence}}",
+    ),
+    (
+        messages::HEARTBEAT_UNREADABLE,
+        "Не удалось прочитать пульс {{heartbeat}} из файла {{path}}. {{t consequence}}",
+    ),
+    (
+        messages::GRACE_PERIOD_EXPIRED,
+        "Льготный период истёк. Перезагружаем машину",
+    ),
+    (
+        messages::DRY_RUN_NOT_RESETTING,
+        "Пробный режим: на самом деле машина не перезагружается",
+    ),
+    (
+        messages::WILL_RESET_IN,
+        "Машина будет перезагружена через {{duration seconds}}, если проблема не будет устранена",
+    ),
+    (
+        messages::WILL_RESET_IN_UNDER_A_MINUTE,
+        "Машина будет перезагружена меньше чем через минуту, если проблема не будет устранена",
+    ),
+    (
+        messages::COUNTDOWN_RECOVERED,
+        "Льготный период закончился: машина восстановилась",
+    ),
+    (
+        messages::COUNTDOWN_RESET_SKIPPED,
+        "Льготный период закончился: перезагрузка пропущена в пробном режиме",
+    ),
+    (
+        messages::COUNTDOWN_RESET_PERFORMED,
+        "Льготный период закончился: машина перезагружена",
+    ),
+    (
+        messages::COUNTDOWN_ESCALATING,
+        "Льготный период закончился: идёт эскалация",
+    ),
+    (
+        messages::COUNTDOWN_RESET_FAILED,
+        "Льготный период закончился: перезагрузка не удалась",
+    ),
+    (
+        messages::COUNTDOWN_POWERED_OFF,
+        "Льготный период закончился: машина выключена",
+    ),
+    (
+        messages::COUNTDOWN_ACKNOWLEDGED,
+        "Отсчёт остановлен: проблема принята",
+    ),
+    (
+        messages::COUNTDOWN_SNOOZED,
+        "Отсчёт остановлен: мониторинг приостановлен",
+    ),
+    (messages::COUNTDOWN_STOPPED, "Отсчёт остановлен"),
+    (
+        messages::SNOOZED_UNTIL,
+        "Мониторинг приостановлен до {{until}}",
+    ),
+    (
+        messages::ACKNOWLEDGED,
+        "Проблема принята, машина не будет перезагружена, пока снова не сообщит, что всё в порядке",
+    ),
+    (
+        messages::NOTHING_TO_ACKNOWLEDGE,
+        "Нечего принимать: у машины не идёт льготный период",
+    ),
+    (messages::RESET_REQUESTED, "Перезагрузка запрошена командой"),
+    (
+        messages::RESET_REQUESTED_ALREADY_RESETTING,
+        "Перезагрузка запрошена командой, но машина уже перезагружается",
+    ),
+    (
+        messages::RESET_REQUESTED_POWERED_OFF,
+        "Перезагрузка запрошена командой, но машина выключена",
+    ),
+    (messages::DRY_RUN_ON, "Пробный режим включён"),
+    (messages::DRY_RUN_OFF, "Пробный режим выключен"),
+    (
+        messages::ESCALATION_STEP,
+        "Шаг эскалации {{step}}/{{steps}}: {{t action}}",
+    ),
+    (messages::AGENT_SHUTDOWN, "выключение через гостевой агент"),
+    (messages::ACPI_SHUTDOWN, "выключение по ACPI"),
+    (messages::HARD_RESET, "жёсткая перезагрузка"),
+    (messages::STOP_START, "жёсткая остановка и запуск"),
+    (
+        messages::STEP_FAILED,
+        "Шаг эскалации {{step}} не удался: {{error}}",
+    ),
+    (messages::RESET_TASK_DONE, "Задача перезагрузки {{outcome}}"),
+    (
+        messages::STEP_TASK_FAILED,
+        "Шаг эскалации {{step}} не удался: задача {{outcome}}",
+    ),
+    (
+        messages::TASK_UNCONFIRMED,
+        "Не удалось подтвердить, что задача перезагрузки {{upid}} выполнена: {{error}}",
+    ),
+    (
+        messages::ALL_STEPS_FAILED,
+        "Все шаги эскалации не удались, машина НЕ перезагружена. Повторим в {{retry_time}}",
+    ),
+    (
+        messages::DIAGNOSTICS_FAILED,
+        "Не удалось сохранить диагностику: {{error}}",
+    ),
+    (
+        messages::DIAGNOSTICS_COLLECTED,
+        "Диагностика сохранена в {{dir}}",
+    ),
+    (
+        messages::DIAGNOSTICS_PARTLY_COLLECTED,
+        "Диагностика сохранена в {{dir}}, но некоторые шаги не удались:\n{{errors}}",
+    ),
+    (
+        messages::SCREENSHOT_CAPTION,
+        "Консоль прямо перед перезагрузкой",
+    ),
+    (
+        messages::NO_REBOOT_HOST_UPTIME,
+        "Команда перезагрузки принята, но гость не перезагрузился: его аптайм {{uptime}} с, до перезагрузки был {{uptime_before}} с, а перезагрузка была {{since_reset}} с назад",
+    ),
+    (
+        messages::UPTIME_UNREADABLE,
+        "Не удалось проверить, что гость перезагрузился: его аптайм не читается: {{error}}",
+    ),
+    (
+        messages::NO_REBOOT_GUEST_UPTIME,
+        "Команда перезагрузки принята, но гость не перезагрузился: его аптайм {{uptime}} с, а перезагрузка была {{since_reset}} с назад",
+    ),
+    (
+        messages::DIGEST_HEADING,
+        "Событий за последние {{window}} с: {{count}}",
+    ),
+    (
+        messages::QUIET_HOURS_HEADING,
+        "Событий за тихие часы: {{count}}",
+    ),
+    (
+        messages::RATE_LIMITED_HEADING,
+        "Из-за ограничения частоты не отправлено сообщений: {{count}}",
+    ),
+    (
+        messages::DIGEST_SECTION,
+        "Узел {{node}}, {{kind}} ({{count}}):",
+    ),
+    (
+        messages::AUTH_FAILED,
+        "Watchdog не может авторизоваться в API Proxmox, мониторинг приостановлен: {{error}}",
+    ),
+];
+
+/// How one notifier puts events into words.
+pub struct Templates {
+    language: config::Language,
+    handlebars: handlebars::Handlebars<'static>,
+}
+
+impl Templates {
+    /// `overrides` replace the whole text of the events of their kind.
+    /// They can use `{{subject}}`, `{{text}}`, `{{kind}}`, `{{severity}}`,
+    /// and all of the event's variables.
+    pub fn new(
+        language: config::Language,
+        overrides: &BTreeMap<config::EventKind, String>,
+    ) -> Result<Self, notify::Error> {
+        let mut handlebars = handlebars::Handlebars::new();
+        // The text goes to chats and emails, not into HTML.
+        handlebars.register_escape_fn(handlebars::no_escape);
+        handlebars.register_helper(
+            "t",
+            Box::new(
+                move |h: &handlebars::Helper,
+                      _: &handlebars::Handlebars,
+                      _: &handlebars::Context,
+                      _: &mut handlebars::RenderContext,
+                      out: &mut dyn handlebars::Output|
+                      -> handlebars::HelperResult {
+                    let text = h.param(0).and_then(|p| p.value().as_str()).unwrap_or("");
+                    out.write(translate(language, text))?;
+                    Ok(())
+                },
+            ),
+        );
+        handlebars.register_helper(
+            "duration",
+            Box::new(
+                move |h: &handlebars::Helper,
+                      _: &handlebars::Handlebars,
+                      _: &handlebars::Context,
+                      _: &mut handlebars::RenderContext,
+                      out: &mut dyn handlebars::Output|
+                      -> handlebars::HelperResult {
+                    let seconds = h.param(0).and_then(|p| match p.value() {
+                        serde_json::Value::Number(n) => n.as_u64(),
+                        serde_json::Value::String(s) => s.parse().ok(),
+                        _ => None,
+                    });
+                    out.write(&duration(language, seconds.unwrap_or_default()))?;
+                    Ok(())
+                },
+            ),
+        );
+
+        let subject = match language {
+            config::Language::En => EN_SUBJECT,
+            config::Language::Ru => RU_SUBJECT,
+        };
+        register(&mut handlebars, "subject", subject)?;
+        register(&mut handlebars, "full_text", FULL_TEXT)?;
+        for (kind, template) in overrides {
+            register(&mut handlebars, &kind_name(*kind), template)?;
+        }
+
+        Ok(Self {
+            language,
+            handlebars,
+        })
+    }
+
+    /// English without overrides.
+    #[cfg(test)]
+    pub fn english() -> Self {
+        Self::new(config::Language::En, &BTreeMap::new()).expect("bundled templates are valid")
+    }
+
+    pub fn render(&self, event: &notify::Event) -> notify::Message {
+        let mut vars: serde_json::Map<String, serde_json::Value> = event
+            .vars
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+
+        let kind = kind_name(event.kind);
+        vars.insert("kind".into(), kind.clone().into());
+        vars.insert(
+            "severity".into(),
+            serde_json::to_value(event.severity).unwrap_or_default(),
+        );
+
+        let text = self.render_one(translate(self.language, &event.template), &vars);
+        vars.insert("text".into(), text.clone().into());
+        let subject = self.render_named("subject", &vars);
+        vars.insert("subject".into(), subject.clone().into());
+
+        let templated = self.handlebars.has_template(&kind);
+        let full_text = if templated {
+            self.render_named(&kind, &vars)
+        } else {
+            self.render_named("full_text", &vars)
+        };
+
+        notify::Message {
+            vmid: event.vmid.clone(),
+            friendly_name: event.friendly_name.clone(),
+            node: event.vars.get("node").cloned().unwrap_or_default(),
+            kind: event.kind,
+            severity: event.severity,
+            subject,
+            text,
+            full_text,
+            templated,
+            attachment: event.attachment.clone(),
+        }
+    }
+
+    /// Put several messages into one, grouped by node and kind of event.
+    /// The `heading` template can use `{{count}}` and `{{window}}`.
+    pub fn render_digest(
+        &self,
+        heading: &str,
+        window: u64,
+        messages: &[notify::Message],
+    ) -> notify::Message {
+        let mut groups: BTreeMap<(&str, config::EventKind), Vec<&notify::Message>> =
+            BTreeMap::new();
+        for message in messages {
+            groups
+                .entry((&message.node, message.kind))
+                .or_default()
+                .push(message);
+        }
+
+        let mut vars = serde_json::Map::new();
+        vars.insert("count".into(), messages.len().into());
+        vars.insert("window".into(), window.into());
+        let subject = self.render_one(translate(self.language, heading), &vars);
+
+        let mut sections = Vec::new();
+        for ((node, kind), group) in &groups {
+            vars.insert(
+                "node".into(),
+                if node.is_empty() { "?" } else { node }.into(),
+            );
+            vars.insert("kind".into(), kind_name(*kind).into());
+            vars.insert("count".into(), group.len().into());
+            let mut section =
+                self.render_one(translate(self.language, messages::DIGEST_SECTION), &vars);
+            for message in group {
+                section += "\n";
+                section += &message.full_text;
+            }
+            sections.push(section);
+        }
+        let text = sections.join("\n\n");
+
+        let most_severe = messages
+            .iter()
+            .max_by_key(|message| message.severity)
+            .expect("a digest has messages");
+        let (mut vmids, mut friendly_names) = (Vec::new(), Vec::new());
+        for message in messages {
+            if !vmids.contains(&message.vmid.as_str()) {
+                vmids.push(message.vmid.as_str());
+                friendly_names.push(message.friendly_name.as_str());
+            }
+        }
+
+        notify::Message {
+            vmid: vmids.join(", "),
+            friendly_name: friendly_names.join(", "),
+            node: String::new(),
+            kind: most_severe.kind,
+            severity: most_severe.severity,
+            full_text: format!("{subject}\n\n{text}"),
+            subject,
+            text,
+            templated: false,
+            attachment: None,
+        }
+    }
+
+    /// Rendering only fails on broken templates,
+    /// and a broken message is better than none.
+    fn render_one(
+        &self,
+        template: &str,
+        vars: &serde_json::Map<String, serde_json::Value>,
+    ) -> String {
+        self.handlebars
+            .render_template(template, vars)
+            .unwrap_or_else(|why| {
+                tracing::error!("Failed to render message {:?}: {}", template, why);
+                template.to_string()
+            })
+    }
+
+    fn render_named(
+        &self,
+        name: &str,
+        vars: &serde_json::Map<String, serde_json::Value>,
+    ) -> String {
+        self.handlebars.render(name, vars).unwrap_or_else(|why| {
+            tracing::error!("Failed to render template {}: {}", name, why);
+            vars.get("text")
+                .and_then(|text| text.as_str())
+                .unwrap_or_default()
+                .to_string()
+        })
+    }
+}
+
+fn register(
+    handlebars: &mut handlebars::Handlebars<'static>,
+    name: &str,
+    template: &str,
+) -> Result<(), notify::Error> {
+    handlebars
+        .register_template_string(name, template)
+        .map_err(|why| notify::Error::Config(format!("invalid template {name}: {why}")))
+}
+
+fn kind_name(kind: config::EventKind) -> String {
+    serde_json::to_value(kind)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .expect("event kinds serialize to strings")
+}
+
+fn translate(language: config::Language, text: &str) -> &str {
+    let catalog = match language {
+        config::Language::En => return text,
+        config::Language::Ru => RU,
+    };
+    catalog
+        .iter()
+        .find(|(en, _)| *en == text)
+        .map(|(_, translated)| *translated)
+        .unwrap_or(text)
+}
+
+/// Like `10 minutes` or `2 hours`, as in "will reset in ...".
+fn duration(language: config::Language, seconds: u64) -> String {
+    let (amount, unit) = if seconds >= 3600 && seconds.is_multiple_of(3600) {
+        (seconds / 3600, 2)
+    } else if seconds >= 60 {
+        (seconds.div_ceil(60), 1)
+    } else {
+        (seconds, 0)
+    };
+
+    match language {
+        config::Language::En => {
+            let unit = ["second", "minute", "hour"][unit];
+            if amount == 1 {
+                format!("1 {unit}")
+            } else {
+                format!("{amount} {unit}s")
+            }
+        }
+        config::Language::Ru => {
+            // Accusative, as in "через 5 минут".
+            let forms = [
+                ["секунду", "секунды", "секунд"],
+                ["минуту", "минуты", "минут"],
+                ["час", "часа", "часов"],
+            ][unit];
+            let form = match (amount % 10, amount % 100) {
+                (_, 11..=14) => forms[2],
+                (1, _) => forms[0],
+                (2..=4, _) => forms[1],
+                _ => forms[2],
+            };
+            format!("{amount} {form}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::tests::event;
+
+    /// What's inside each `{{...}}`, like `duration seconds`.
+    fn placeholders(template: &str) -> Vec<&str> {
+        let mut found: Vec<&str> = template
+            .split("{{")
+            .skip(1)
+            .filter_map(|part| part.split_once("}}").map(|(inside, _)| inside))
+            .collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn every_message_has_a_russian_translation() {
+        let mut templates = Templates::new(config::Language::Ru, &BTreeMap::new()).unwrap();
+        templates.handlebars.set_strict_mode(true);
+        for &message in messages::ALL {
+            let translated = translate(config::Language::Ru, message);
+            assert_ne!(translated, message, "no translation for {message:?}");
+
+            // Every variable is set to something `duration` and `t` can take.
+            let vars: serde_json::Map<String, serde_json::Value> = placeholders(translated)
+                .iter()
+                .map(|inside| (inside.split(' ').next_back().unwrap().into(), "60".into()))
+                .collect();
+            if let Err(why) = templates.handlebars.render_template(translated, &vars) {
+                panic!("{translated:?} does not render: {why}");
+            }
+        }
+    }
+
+    #[test]
+    fn russian_translations_use_the_same_variables() {
+        for (en, ru) in RU {
+            assert_eq!(placeholders(en), placeholders(ru), "in {ru:?}");
+        }
+    }
+
+    #[test]
+    fn t_falls_back_to_english() {
+        let templates = Templates::new(config::Language::Ru, &BTreeMap::new()).unwrap();
+        let mut consequence = event(config::EventKind::GracePeriod, "{{t consequence}}");
+        consequence.vars.insert(
+            "consequence".to_string(),
+            "Grace period started".to_string(),
+        );
+        assert_eq!(templates.render(&consequence).text, "Начат льготный период");
+
+        consequence.vars.insert(
+            "consequence".to_string(),
+            "Something nobody translated".to_string(),
+        );
+        assert_eq!(
+            templates.render(&consequence).text,
+            "Something nobody translated"
+        );
+
+        let untranslated = event(config::EventKind::Monitoring, "Not in the catalog");
+        let message = templates.render(&untranslated);
+        assert_eq!(message.text, "Not in the catalog");
+        assert_eq!(message.full_text, "ВМ 100 (web): Not in the catalog");
+    }
+
+    #[test]
+    fn russian_durations_agree_with_the_number() {
+        let minutes = |amount: u64| duration(config::Language::Ru, amount * 60);
+        assert_eq!(minutes(1), "1 минуту");
+        assert_eq!(minutes(2), "2 минуты");
+        assert_eq!(minutes(5), "5 минут");
+        assert_eq!(minutes(11), "11 минут");
+        assert_eq!(minutes(21), "21 минуту");
+        assert_eq!(minutes(22), "22 минуты");
+        assert_eq!(duration(config::Language::Ru, 1), "1 секунду");
+        assert_eq!(duration(config::Language::Ru, 2 * 3600), "2 часа");
+        assert_eq!(duration(config::Language::Ru, 5 * 3600), "5 часов");
+        assert_eq!(duration(config::Language::En, 60), "1 minute");
+        assert_eq!(duration(config::Language::En, 90), "2 minutes");
+    }
+
+    #[test]
+    fn override_replaces_the_text_of_its_kind() {
+        let overrides = [(
+            config::EventKind::Reset,
+            "[{{severity}}] {{kind}} on {{node}}: {{text}}".to_string(),
+        )]
+        .into();
+        let templates = Templates::new(config::Language::En, &overrides).unwrap();
+
+        let reset = templates.render(&event(config::EventKind::Reset, "Resetting {{vmid}}"));
+        assert!(reset.templated);
+        assert_eq!(reset.text, "Resetting 100");
+        assert_eq!(reset.full_text, "[critical] reset on pve1: Resetting 100");
+        assert_eq!(reset.body(), "[critical] reset on pve1: Resetting 100");
+
+        let other = templates.render(&event(config::EventKind::Recovered, "Machine is OK"));
+        assert!(!other.templated);
+        assert_eq!(other.full_text, "VMID 100 (web): Machine is OK");
+    }
+
+    #[test]
+    fn broken_override_is_a_config_error() {
+        let overrides = [(config::EventKind::Reset, "{{#if}}".to_string())].into();
+        assert!(matches!(
+            Templates::new(config::Language::En, &overrides),
+            Err(notify::Error::Config(_))
+        ));
+    }
+}
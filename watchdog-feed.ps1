@@ -0,0 +1,20 @@
+# Windows counterpart of watchdog-feed.service, for guests with guest_os set to windows.
+# Run it every minute, for example with:
+#
+#   New-Item -ItemType Directory -Force C:\ProgramData\soft-watchdog
+#   $action = New-ScheduledTaskAction -Execute powershell.exe -Argument '-NoProfile -ExecutionPolicy Bypass -File C:\ProgramData\soft-watchdog\watchdog-feed.ps1'
+#   $trigger = New-ScheduledTaskTrigger -Once -At (Get-Date) -RepetitionInterval (New-TimeSpan -Minutes 1)
+#   Register-ScheduledTask -TaskName watchdog-feed -Action $action -Trigger $trigger -User SYSTEM
+
+$dir = 'C:\ProgramData\soft-watchdog'
+$currentTimeFile = Join-Path $dir 'watchdog_current_unix_time'
+
+if (Test-Path $currentTimeFile) {
+    $currentTime = [long](Get-Content $currentTimeFile -Raw).Trim()
+    Set-Content -Path (Join-Path $dir 'watchdog_reset_after') -Value ($currentTime + 90) -Encoding ASCII
+}
+
+# To only run if the service called example is running, use this instead:
+## if ((Get-Service example).Status -eq 'Running') { ... } else {
+##     Set-Content -Path (Join-Path $dir 'watchdog_reset_after') -Value '{"status": "failing", "reason": "example is down"}' -Encoding ASCII
+## }